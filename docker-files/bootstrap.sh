@@ -20,4 +20,5 @@ fi
 echo "Starting program"
 cd /data
 ls
-/insa-scan
+# exec so that the scanner gets the SIGTERM of docker stop
+exec /insa-scan
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use axum::{Json, Router};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::routing::get;
+use serde::{Serialize, Deserialize};
+use tokio::sync::RwLock;
+use crate::{metrics, now_utc, rooms, ExtendedInfo, MachineState, States};
+use crate::hardware::HardwareInfo;
+use crate::identity::{Fleet, Identity, Lease};
+use crate::inventory::{reassignments, Reassignment};
+use crate::load::LoadSample;
+use crate::probe::ProbeDetails;
+use crate::recommend::{recommend, Recommendation};
+use crate::rooms::room_of;
+use crate::ssh::SshHost;
+
+type SharedStates = Arc<RwLock<States>>;
+/// Updated on each save, see `scan`
+type SharedFleet = Arc<RwLock<Fleet>>;
+
+#[derive(Clone)]
+struct ApiState {
+    states: SharedStates,
+    fleet: SharedFleet,
+}
+
+#[derive(Serialize)]
+struct Machine {
+    ip: Ipv4Addr,
+    hostname: Option<String>,
+    room: &'static str,
+    up: bool,
+    /// Time of the last state change
+    since: u64,
+    last_checked: u64,
+    /// Share of the last 30 days the machine was up, between 0 and 1
+    reliability: Option<f64>,
+    cpu: Option<String>,
+    /// Bytes
+    ram: Option<u64>,
+    /// Bytes
+    swap: Option<u64>,
+    mac: Option<String>,
+    hardware: Option<HardwareInfo>,
+    /// Time the hostname and hardware were collected
+    info_collected_at: Option<u64>,
+    /// Time and message of the last failed collection, if it failed since
+    info_error: Option<(u64, String)>,
+    last_probe: Option<ProbeDetails>,
+    ssh_host: Option<SshHost>,
+    /// Latest sample, if taken since the machine came up
+    load: Option<LoadSample>,
+    /// 1-minute load average divided by the number of threads
+    load_per_thread: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct MachineDetails {
+    #[serde(flatten)]
+    machine: Machine,
+    changes: Vec<Change>,
+    /// Oldest first
+    load_history: Vec<LoadSample>,
+    /// Previous extended info, oldest first, one snapshot per change
+    info_history: Vec<ExtendedInfo>,
+    /// Machines that used this IP, oldest first
+    leases: Vec<Lease>,
+}
+
+/// A machine followed across the IPs it used
+#[derive(Serialize)]
+struct TrackedMachineSummary {
+    identity: Identity,
+    /// IP of the latest lease
+    ip: Ipv4Addr,
+    hostname: Option<String>,
+    room: &'static str,
+    up: bool,
+    since: u64,
+    /// Share of the last 30 days the machine was up, at any of its IPs
+    reliability: Option<f64>,
+    leases: Vec<Lease>,
+}
+
+#[derive(Serialize)]
+struct Change {
+    ip: Ipv4Addr,
+    time: u64,
+    up: bool,
+}
+
+#[derive(Serialize)]
+struct Room {
+    name: &'static str,
+    building: Option<String>,
+    floor: Option<i32>,
+    capacity: Option<u32>,
+    /// According to the opening hours of the catalog
+    open: bool,
+    up_count: usize,
+    machine_count: usize,
+}
+
+#[derive(Deserialize)]
+struct MachineFilter {
+    up: Option<bool>,
+    room: Option<String>,
+    /// Case-insensitive substring of the CPU model
+    cpu: Option<String>,
+    min_ram_gb: Option<f64>,
+    #[serde(default)]
+    sort: MachineSort,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MachineSort {
+    #[default]
+    Ip,
+    /// Least loaded first, machines without a recent sample last
+    Load,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Defaults to 7 days ago
+    from: Option<u64>,
+    /// Defaults to now
+    to: Option<u64>,
+    ip: Option<Ipv4Addr>,
+}
+
+#[derive(Deserialize)]
+struct ReassignmentQuery {
+    /// Defaults to 30 days ago
+    since: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RecommendationQuery {
+    count: Option<usize>,
+}
+
+/// Machines that have been seen up at least once
+fn is_known(state: &MachineState, now_utc: u64) -> bool {
+    let (up, uptime, _) = state.times_since(0, now_utc);
+    up || uptime > 0
+}
+
+fn machine(ip: Ipv4Addr, state: &MachineState, now_utc: u64) -> Machine {
+    let info = state.extended_info.as_ref();
+    let (up, uptime, downtime) = state.times_since(now_utc.saturating_sub(30*86400), now_utc);
+    Machine {
+        ip,
+        hostname: info.map(|info| info.hostname.clone()),
+        room: room_of(ip, info.map(|info| info.hostname.as_str()).unwrap_or("")),
+        up,
+        since: state.last_change(),
+        last_checked: state.last_checked(),
+        reliability: (uptime + downtime > 0).then(|| uptime as f64 / (uptime + downtime) as f64),
+        cpu: info.and_then(|info| info.hardware.cpu_model.clone()),
+        ram: info.and_then(|info| info.hardware.ram),
+        swap: info.and_then(|info| info.hardware.swap),
+        mac: info.and_then(|info| info.hardware.mac()).map(String::from),
+        hardware: info.map(|info| info.hardware.clone()),
+        info_collected_at: info.map(|info| info.collected_at).filter(|t| *t > 0),
+        info_error: state.last_info_error.clone(),
+        last_probe: state.last_probe.clone(),
+        ssh_host: state.ssh_host.clone(),
+        load: state.current_load().cloned(),
+        load_per_thread: Some(state.load_score()).filter(|score| score.is_finite()),
+    }
+}
+
+fn changes(ip: Ipv4Addr, state: &MachineState) -> impl Iterator<Item = Change> + '_ {
+    state.changes.iter().enumerate().map(move |(i, time)| Change { ip, time: *time, up: i % 2 == 0 })
+}
+
+async fn get_machines(State(ApiState { states, .. }): State<ApiState>, Query(filter): Query<MachineFilter>) -> Json<Vec<Machine>> {
+    let now_utc = now_utc();
+    let states = states.read().await;
+    let cpu_filter = filter.cpu.map(|cpu| cpu.to_lowercase());
+    let mut machines: Vec<Machine> = states.iter()
+        .filter(|(_, state)| is_known(state, now_utc))
+        .map(|(ip, state)| machine(*ip, state, now_utc))
+        .filter(|m| filter.up.is_none_or(|up| m.up == up))
+        .filter(|m| filter.room.as_ref().is_none_or(|room| m.room == room))
+        .filter(|m| cpu_filter.as_ref().is_none_or(|cpu| m.cpu.as_ref().is_some_and(|c| c.to_lowercase().contains(cpu))))
+        .filter(|m| filter.min_ram_gb.is_none_or(|min| m.ram.is_some_and(|ram| ram as f64 / 1_000_000_000.0 >= min)))
+        .collect();
+    match filter.sort {
+        MachineSort::Ip => machines.sort_by_key(|m| m.ip),
+        MachineSort::Load => machines.sort_by(|m1, m2| {
+            let score = |m: &Machine| m.load_per_thread.unwrap_or(f32::INFINITY);
+            score(m1).total_cmp(&score(m2)).then(m1.ip.cmp(&m2.ip))
+        }),
+    }
+    Json(machines)
+}
+
+async fn get_machine(State(ApiState { states, fleet }): State<ApiState>, Path(ip): Path<Ipv4Addr>) -> Result<Json<MachineDetails>, StatusCode> {
+    let now_utc = now_utc();
+    let leases = fleet.read().await.leases.get(&ip).cloned().unwrap_or_default();
+    let states = states.read().await;
+    let state = states.get(&ip).filter(|state| is_known(state, now_utc)).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(MachineDetails {
+        machine: machine(ip, state, now_utc),
+        changes: changes(ip, state).collect(),
+        load_history: state.load.clone(),
+        info_history: state.info_history.clone(),
+        leases,
+    }))
+}
+
+async fn get_identities(State(ApiState { fleet, .. }): State<ApiState>) -> Json<Vec<TrackedMachineSummary>> {
+    let now_utc = now_utc();
+    let fleet = fleet.read().await;
+    let machines = fleet.machines.iter()
+        .filter(|machine| is_known(&machine.state, now_utc))
+        .map(|machine| {
+            let info = machine.state.extended_info.as_ref();
+            let (up, uptime, downtime) = machine.state.times_since(now_utc.saturating_sub(30*86400), now_utc);
+            TrackedMachineSummary {
+                ip: machine.ip(),
+                hostname: info.map(|info| info.hostname.clone()),
+                room: room_of(machine.ip(), info.map(|info| info.hostname.as_str()).unwrap_or("")),
+                up,
+                since: machine.state.last_change(),
+                reliability: (uptime + downtime > 0).then(|| uptime as f64 / (uptime + downtime) as f64),
+                identity: machine.identity.clone(),
+                leases: machine.leases.clone(),
+            }
+        })
+        .collect();
+    Json(machines)
+}
+
+async fn get_rooms(State(ApiState { fleet, .. }): State<ApiState>) -> Json<Vec<Room>> {
+    let now_utc = now_utc();
+    let fleet = fleet.read().await;
+    let catalog = rooms::catalog();
+    let mut rooms = BTreeMap::new();
+    // Machines that got another IP are counted once
+    for machine in &fleet.machines {
+        let state = &machine.state;
+        let (up, uptime, _) = state.times_since(now_utc.saturating_sub(30*86400), now_utc);
+        if uptime == 0 {
+            continue;
+        }
+        let hostname = state.extended_info.as_ref().map(|info| info.hostname.as_str()).unwrap_or("");
+        let name = room_of(machine.ip(), hostname);
+        let room = rooms.entry(catalog.names().position(|n| n == name)).or_insert_with(|| {
+            let info = catalog.get(name);
+            Room {
+                name,
+                building: info.and_then(|info| info.building.clone()),
+                floor: info.and_then(|info| info.floor),
+                capacity: info.and_then(|info| info.capacity),
+                open: info.is_none_or(|info| info.is_open(now_utc)),
+                up_count: 0,
+                machine_count: 0,
+            }
+        });
+        room.machine_count += 1;
+        if up {
+            room.up_count += 1;
+        }
+    }
+    Json(rooms.into_values().collect())
+}
+
+async fn get_history(State(ApiState { states, .. }): State<ApiState>, Query(query): Query<HistoryQuery>) -> Json<Vec<Change>> {
+    let now_utc = now_utc();
+    let from = query.from.unwrap_or(now_utc.saturating_sub(7*86400));
+    let to = query.to.unwrap_or(now_utc);
+    let states = states.read().await;
+    let mut history: Vec<Change> = states.iter()
+        .filter(|(ip, _)| query.ip.is_none_or(|wanted| **ip == wanted))
+        .filter(|(_, state)| is_known(state, now_utc))
+        .flat_map(|(ip, state)| changes(*ip, state))
+        .filter(|change| (from..=to).contains(&change.time))
+        .collect();
+    history.sort_by_key(|change| (change.time, change.ip));
+    Json(history)
+}
+
+async fn get_reassignments(State(ApiState { states, .. }): State<ApiState>, Query(query): Query<ReassignmentQuery>) -> Json<Vec<Reassignment>> {
+    let since = query.since.unwrap_or(now_utc().saturating_sub(30*86400));
+    let states = states.read().await;
+    Json(reassignments(&states, since))
+}
+
+async fn get_recommendations(State(ApiState { fleet, .. }): State<ApiState>, Query(query): Query<RecommendationQuery>) -> Json<Vec<Recommendation>> {
+    let fleet = fleet.read().await;
+    Json(recommend(&fleet.machines, now_utc(), query.count.unwrap_or(5)))
+}
+
+async fn get_metrics(State(ApiState { fleet, .. }): State<ApiState>) -> ([(header::HeaderName, &'static str); 1], String) {
+    let fleet = fleet.read().await;
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render(&fleet, now_utc()))
+}
+
+pub async fn serve(listen: &str, states: SharedStates, fleet: SharedFleet) -> Result<(), String> {
+    let app = Router::new()
+        .route("/machines", get(get_machines))
+        .route("/machines/{ip}", get(get_machine))
+        .route("/identities", get(get_identities))
+        .route("/rooms", get(get_rooms))
+        .route("/history", get(get_history))
+        .route("/reassignments", get(get_reassignments))
+        .route("/recommendations", get(get_recommendations))
+        .route("/metrics", get(get_metrics))
+        .with_state(ApiState { states, fleet });
+    let listener = tokio::net::TcpListener::bind(listen).await.map_err(|e| format!("Failed to listen on {listen}: {e}"))?;
+    axum::serve(listener, app).await.map_err(|e| format!("API server failed: {e}"))
+}
@@ -0,0 +1,77 @@
+use std::net::Ipv4Addr;
+use clap::{Parser, Subcommand};
+use crate::targets::TargetRange;
+
+/// Command line flags. They take precedence over environment variables, which take precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(version, about = "Scanner for the INSA network")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Path to the TOML config file
+    #[arg(long, global = true, env = "INSA_SCAN_CONFIG")]
+    pub config: Option<String>,
+    /// Directory where states.bin, stats.csv, the SSH key and the site live
+    #[arg(long, global = true, env = "DATA_DIR")]
+    pub data_dir: Option<String>,
+    /// Username used to load extended info over SSH
+    #[arg(long, global = true, env = "INSA_USERNAME")]
+    pub username: Option<String>,
+    /// Range to scan (replaces the ranges of the config file, can be repeated)
+    #[arg(long = "target", global = true)]
+    pub targets: Vec<TargetRange>,
+    /// Range to exclude from the scan (can be repeated)
+    #[arg(long = "exclude", global = true)]
+    pub excludes: Vec<TargetRange>,
+    /// Maximum number of concurrent probes
+    #[arg(long, global = true)]
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug, Default, Subcommand)]
+pub enum Command {
+    /// Scan the network forever (default)
+    #[default]
+    Scan,
+    /// Check the machines that are due once and exit
+    ScanOnce,
+    /// Regenerate stats.csv from states.bin
+    Stats,
+    /// Regenerate site/index.html from states.bin
+    Site,
+    /// Print the timeline of a machine
+    Inspect {
+        ip: Ipv4Addr,
+    },
+    /// List the machines most likely to stay up
+    Recommend {
+        /// Number of machines to list
+        #[arg(short = 'n', long, default_value_t = 5)]
+        count: usize,
+    },
+    /// Predict which machines will be up at a given time
+    Predict {
+        /// UNIX timestamp or RFC 3339 date (defaults to now)
+        #[arg(long, value_parser = parse_time)]
+        at: Option<u64>,
+    },
+    /// List machines that moved to another IP and IPs that changed hostname
+    Reassignments {
+        /// UNIX timestamp or RFC 3339 date (defaults to 30 days ago)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<u64>,
+    },
+    /// Rewrite states.bin in the current format
+    Migrate,
+    /// Rebuild states from the journal
+    Replay,
+}
+
+fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(timestamp) = s.parse() {
+        return Ok(timestamp);
+    }
+    chrono::DateTime::parse_from_rfc3339(s)
+        .map(|time| time.timestamp() as u64)
+        .map_err(|e| format!("Expected a UNIX timestamp or an RFC 3339 date: {e}"))
+}
@@ -0,0 +1,419 @@
+use std::time::Duration;
+use serde::Deserialize;
+use crate::cli::Args;
+use crate::error::Error;
+use crate::probe::{ProbeKind, ProbeRule};
+use crate::rooms::OpeningHours;
+use crate::targets::Targets;
+
+/// Default location of the config file, relative to the working directory
+const DEFAULT_CONFIG_PATH: &str = "insa-scan.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: String,
+    pub username: Option<String>,
+    pub targets: Targets,
+    pub probe: ProbeConfig,
+    pub scan: ScanConfig,
+    pub schedule: ScheduleConfig,
+    pub politeness: PolitenessConfig,
+    pub ssh: SshConfig,
+    pub refresh: RefreshConfig,
+    pub load: LoadConfig,
+    pub persistence: PersistenceConfig,
+    pub storage: StorageConfig,
+    pub journal: JournalConfig,
+    pub rooms: RoomsConfig,
+    pub api: ApiConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    /// Connection timeout for machines that were up at the previous check
+    pub timeout_up_secs: u64,
+    /// Connection timeout for machines that were down at the previous check
+    pub timeout_down_secs: u64,
+    /// How machines are checked, unless a rule matches
+    pub method: ProbeKind,
+    /// Other methods for some target ranges, the first matching rule wins
+    pub rules: Vec<ProbeRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    /// Maximum number of concurrent probes
+    pub concurrency: usize,
+    /// Maximum number of probes started per second
+    pub probes_per_sec: f64,
+    /// States, stats and site are saved every time this number of machines have been checked
+    pub save_every: usize,
+    /// How often the checks, limits and schedule report are logged and the metrics of the period updated, and the period of `refresh.max_per_round`
+    pub period_secs: u64,
+    /// On SIGTERM or SIGINT, time given to checks in flight to end before the final save
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Maximum time between two checks of machines that were up at the previous one
+    pub up_secs: u64,
+    /// Of machines that were up during the last `recent_secs`
+    pub recently_up_secs: u64,
+    /// Of machines that were up longer ago
+    pub seen_once_secs: u64,
+    /// Of machines that never answered
+    pub never_seen_secs: u64,
+    pub recent_secs: u64,
+    /// Machines are checked up to this share of their interval early, so that machines checked together drift apart
+    pub jitter: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolitenessConfig {
+    /// Probes that can start at once after an idle time, on top of `scan.probes_per_sec`
+    pub burst: usize,
+    /// Times at which `quiet_probes_per_sec` applies instead of `scan.probes_per_sec`, such as `mon-fri 00:00-07:00`
+    pub quiet_hours: Vec<OpeningHours>,
+    pub quiet_probes_per_sec: f64,
+    /// Length of the prefix of the subnets checks in flight are counted in
+    pub subnet_prefix: u8,
+    /// Maximum number of checks in flight per subnet
+    pub max_per_subnet: usize,
+    /// Probing stops while this file exists, relative to the data dir
+    pub pause_file: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshConfig {
+    /// Timeout of each SSH operation: connection, authentication and each command.
+    /// Nothing bounds their total, a check that logs in holds its SSH slot for up to 15 × (timeout_secs + 1) + 1 seconds.
+    pub timeout_secs: u64,
+    pub port: u16,
+    /// Record the banner and host key of machines when they come up (no credentials needed)
+    pub scan_host_keys: bool,
+    /// Keep the command outputs hardware info is parsed from
+    pub keep_raw: bool,
+    /// Maximum number of SSH connections at once, independently of probes
+    pub concurrency: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    /// Collect extended info again on machines that stay up (it is always collected when they come up)
+    pub enabled: bool,
+    /// Extended info older than this is collected again
+    pub max_age_secs: u64,
+    /// Time before trying again after a failed collection
+    pub retry_failed_secs: u64,
+    /// Maximum number of refreshes every `scan.period_secs`, on top of machines coming up
+    pub max_per_round: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadConfig {
+    /// Sample the load of machines that are up over SSH (needs a username)
+    pub enabled: bool,
+    /// Time between two samples of the same machine
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Number of timestamped copies of states.bin kept in the backups directory (0 disables backups)
+    pub backups: usize,
+    /// Minimum time between two backups
+    pub backup_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Everything in states.bin
+    File,
+    /// Rows in an SQLite database, imported from states.bin on first use
+    Sqlite,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Path of the database, relative to the data dir
+    pub sqlite_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalConfig {
+    /// Log every state transition and extended info update
+    pub enabled: bool,
+    /// Path of the journal, relative to the data dir
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Path of the room catalog, relative to the data dir (the built-in catalog is used if it doesn't exist)
+    pub path: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Address the HTTP API listens on while scanning, such as `0.0.0.0:8080` (disabled if unset)
+    pub listen: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: String::from("."),
+            username: None,
+            targets: Targets::default(),
+            probe: ProbeConfig::default(),
+            scan: ScanConfig::default(),
+            schedule: ScheduleConfig::default(),
+            politeness: PolitenessConfig::default(),
+            ssh: SshConfig::default(),
+            refresh: RefreshConfig::default(),
+            load: LoadConfig::default(),
+            persistence: PersistenceConfig::default(),
+            storage: StorageConfig::default(),
+            journal: JournalConfig::default(),
+            rooms: RoomsConfig::default(),
+            api: ApiConfig::default(),
+        }
+    }
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            timeout_up_secs: 12,
+            timeout_down_secs: 4,
+            method: ProbeKind::default(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            concurrency: 200,
+            probes_per_sec: 100.0,
+            save_every: 500,
+            period_secs: 600,
+            shutdown_timeout_secs: 5,
+        }
+    }
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            up_secs: 600,
+            recently_up_secs: 1200,
+            seen_once_secs: 3600,
+            never_seen_secs: 4*3600,
+            recent_secs: 7*86400,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl Default for PolitenessConfig {
+    fn default() -> Self {
+        PolitenessConfig {
+            burst: 20,
+            quiet_hours: Vec::new(),
+            quiet_probes_per_sec: 10.0,
+            subnet_prefix: 24,
+            max_per_subnet: 32,
+            pause_file: String::from("pause"),
+        }
+    }
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig {
+            timeout_secs: 3,
+            port: 22,
+            scan_host_keys: true,
+            keep_raw: false,
+            concurrency: 20,
+        }
+    }
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        RefreshConfig {
+            enabled: true,
+            max_age_secs: 7*86400,
+            retry_failed_secs: 6*3600,
+            max_per_round: 200,
+        }
+    }
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            enabled: true,
+            interval_secs: 600,
+        }
+    }
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        PersistenceConfig {
+            backups: 24,
+            backup_interval_secs: 3600,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::File,
+            sqlite_path: String::from("states.sqlite"),
+        }
+    }
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            enabled: true,
+            path: String::from("journal.jsonl"),
+        }
+    }
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        RoomsConfig {
+            path: String::from("rooms.toml"),
+        }
+    }
+}
+
+impl ProbeConfig {
+    pub fn timeout(&self, was_up: bool) -> Duration {
+        match was_up {
+            true => Duration::from_secs(self.timeout_up_secs),
+            false => Duration::from_secs(self.timeout_down_secs),
+        }
+    }
+}
+
+impl Config {
+    pub fn journal_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.data_dir).join(&self.journal.path)
+    }
+
+    pub fn rooms_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.data_dir).join(&self.rooms.path)
+    }
+
+    /// Loads the config file and applies the overrides of the command line and environment
+    pub fn load(args: &Args) -> Result<Config, Error> {
+        let mut config = match &args.config {
+            Some(path) => Config::read(path)?,
+            None => match std::path::Path::new(DEFAULT_CONFIG_PATH).exists() {
+                true => Config::read(DEFAULT_CONFIG_PATH)?,
+                false => Config::default(),
+            },
+        };
+
+        if let Some(data_dir) = &args.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(username) = &args.username {
+            config.username = Some(username.clone()).filter(|u| !u.is_empty());
+        }
+        if !args.targets.is_empty() {
+            config.targets.include = args.targets.clone();
+        }
+        config.targets.exclude.extend(args.excludes.iter().copied());
+        if let Some(concurrency) = args.concurrency {
+            config.scan.concurrency = concurrency;
+        }
+
+        config.validate().map_err(Error::Format)?;
+        Ok(config)
+    }
+
+    fn read(path: &str) -> Result<Config, Error> {
+        let content = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        toml::from_str(&content).map_err(|e| Error::Format(format!("Invalid config file {path}: {e}")))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.targets.include.is_empty() {
+            return Err(String::from("targets.include must contain at least one range"));
+        }
+        if self.probe.timeout_up_secs == 0 || self.probe.timeout_down_secs == 0 {
+            return Err(String::from("probe timeouts must be greater than 0"));
+        }
+        for method in std::iter::once(&self.probe.method).chain(self.probe.rules.iter().map(|rule| &rule.probe)) {
+            if matches!(method, ProbeKind::MultiPort { ports, .. } if ports.is_empty()) {
+                return Err(String::from("multi_port probes must have at least one port"));
+            }
+        }
+        if self.scan.concurrency == 0 {
+            return Err(String::from("scan.concurrency must be greater than 0"));
+        }
+        if self.scan.period_secs == 0 {
+            return Err(String::from("scan.period_secs must be greater than 0"));
+        }
+        let schedule = &self.schedule;
+        if [schedule.up_secs, schedule.recently_up_secs, schedule.seen_once_secs, schedule.never_seen_secs].contains(&0) {
+            return Err(String::from("schedule intervals must be greater than 0"));
+        }
+        if self.scan.probes_per_sec.is_nan() || self.scan.probes_per_sec <= 0.0 {
+            return Err(String::from("scan.probes_per_sec must be greater than 0"));
+        }
+        if !(0.0..1.0).contains(&schedule.jitter) {
+            return Err(String::from("schedule.jitter must be between 0 and 1"));
+        }
+        let politeness = &self.politeness;
+        if politeness.burst == 0 {
+            return Err(String::from("politeness.burst must be greater than 0"));
+        }
+        if politeness.quiet_probes_per_sec.is_nan() || politeness.quiet_probes_per_sec <= 0.0 {
+            return Err(String::from("politeness.quiet_probes_per_sec must be greater than 0"));
+        }
+        if politeness.subnet_prefix > 32 {
+            return Err(String::from("politeness.subnet_prefix must be at most 32"));
+        }
+        if politeness.max_per_subnet == 0 {
+            return Err(String::from("politeness.max_per_subnet must be greater than 0"));
+        }
+        if self.scan.save_every == 0 {
+            return Err(String::from("scan.save_every must be greater than 0"));
+        }
+        if self.ssh.timeout_secs == 0 {
+            return Err(String::from("ssh.timeout_secs must be greater than 0"));
+        }
+        if self.ssh.concurrency == 0 {
+            return Err(String::from("ssh.concurrency must be greater than 0"));
+        }
+        Ok(())
+    }
+}
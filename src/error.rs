@@ -0,0 +1,90 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::migrations::CURRENT_VERSION;
+
+/// Number of attempts of an operation that fails with a transient error
+const ATTEMPTS: u32 = 4;
+/// Wait before the second attempt, doubled before each of the next ones
+const FIRST_BACKOFF: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Data that can't be encoded or decoded, such as a corrupted states.bin
+    Format(String),
+    Database(rusqlite::Error),
+    /// A marker of the site template is missing
+    Template(&'static str),
+    /// states.bin was written by a newer version, with this schema version.
+    /// It must be left alone rather than replaced by older data.
+    TooRecent(u32),
+}
+
+impl Error {
+    pub fn io(path: impl AsRef<Path>, source: std::io::Error) -> Error {
+        Error::Io { path: path.as_ref().to_path_buf(), source }
+    }
+
+    /// Whether trying again later may succeed, like when the disk is full or the database is locked
+    pub fn is_transient(&self) -> bool {
+        use std::io::ErrorKind::*;
+        match self {
+            Error::Io { source, .. } => matches!(source.kind(), Interrupted | WouldBlock | TimedOut | WriteZero | StorageFull | QuotaExceeded | ResourceBusy),
+            Error::Database(rusqlite::Error::SqliteFailure(e, _)) => matches!(e.code, rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked),
+            Error::Database(_) | Error::Format(_) | Error::Template(_) | Error::TooRecent(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::Format(message) => write!(f, "{message}"),
+            Error::Database(e) => write!(f, "SQLite error: {e}"),
+            Error::Template(marker) => write!(f, "Marker {marker} is missing from the site template"),
+            Error::TooRecent(version) => write!(f, "states.bin was written by a newer version of insa-scan (schema version {version}, this build supports up to {CURRENT_VERSION})"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Database(e) => Some(e),
+            Error::Format(_) | Error::Template(_) | Error::TooRecent(_) => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+/// Runs an operation again with exponential backoff while it fails with a transient error
+pub async fn with_retries<T, F, Fut>(what: &str, mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut backoff = FIRST_BACKOFF;
+    for attempt in 1.. {
+        match operation().await {
+            Err(e) if e.is_transient() && attempt < ATTEMPTS => {
+                eprintln!("Failed to {what} ({e}), trying again in {}ms", backoff.as_millis());
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            },
+            result => return result,
+        }
+    }
+    unreachable!()
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeSet;
+use serde::{Serialize, Deserialize, Deserializer};
+use crate::ExtendedInfo;
+
+/// Command outputs hardware info is parsed from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RawInfo {
+    /// `cat /proc/cpuinfo`
+    pub cpuinfo: String,
+    /// `cat /proc/meminfo`
+    pub meminfo: String,
+    /// `ip addr`
+    pub ip_addr: String,
+    /// `uname -r`
+    pub kernel: Option<String>,
+    /// `cat /etc/os-release`
+    pub os_release: Option<String>,
+    /// `cat /sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq`
+    pub max_freq: Option<String>,
+    /// `lsblk -bdno NAME,SIZE,TYPE`
+    pub lsblk: Option<String>,
+    /// `lspci`
+    pub lspci: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Nic {
+    pub name: String,
+    pub mac: Option<String>,
+    /// With their prefix length, such as `172.29.4.12/16`
+    pub ips: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Disk {
+    pub name: String,
+    /// Bytes
+    pub size: u64,
+}
+
+/// Hardware of a machine, parsed once when collected
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HardwareInfo {
+    pub cpu_model: Option<String>,
+    /// Physical cores, over all sockets
+    pub cores: Option<u32>,
+    pub threads: Option<u32>,
+    /// Maximum frequency, or the current one when the maximum is unknown
+    pub cpu_mhz: Option<u32>,
+    /// Bytes
+    pub ram: Option<u64>,
+    /// Bytes
+    pub swap: Option<u64>,
+    pub nics: Vec<Nic>,
+    pub kernel: Option<String>,
+    /// `PRETTY_NAME` of /etc/os-release, such as `Ubuntu 22.04.3 LTS`
+    pub os: Option<String>,
+    pub disks: Vec<Disk>,
+    /// Display controllers listed by lspci
+    pub gpus: Vec<String>,
+}
+
+/// Value of a `key : value` line of /proc/cpuinfo
+fn cpuinfo_values<'a>(cpuinfo: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+    cpuinfo.lines().filter_map(move |line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then_some(v.trim())
+    })
+}
+
+/// Kilobytes of a /proc/meminfo line, in bytes
+pub fn meminfo_bytes(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix(':')?;
+        value.trim().trim_end_matches("kB").trim().parse::<u64>().ok().map(|kb| kb * 1000)
+    })
+}
+
+fn parse_nics(ip_addr: &str) -> Vec<Nic> {
+    let mut nics: Vec<Nic> = Vec::new();
+    for line in ip_addr.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            // `2: eth0: <BROADCAST,MULTICAST,UP> mtu 1500 ...`, or `3: veth1@if2: ...`
+            let mut parts = line.splitn(3, ':');
+            let (Some(_), Some(name)) = (parts.next(), parts.next()) else { continue };
+            let name = name.trim().split('@').next().unwrap_or_default();
+            if !name.is_empty() {
+                nics.push(Nic { name: name.to_string(), mac: None, ips: Vec::new() });
+            }
+            continue;
+        }
+        let Some(nic) = nics.last_mut() else { continue };
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("link/ether"), Some(mac)) => nic.mac = Some(mac.to_string()),
+            (Some("inet" | "inet6"), Some(ip)) => nic.ips.push(ip.to_string()),
+            _ => (),
+        }
+    }
+    nics
+}
+
+fn parse_os_release(os_release: &str) -> Option<String> {
+    os_release.lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim().trim_matches('"').to_string())
+        .filter(|name| !name.is_empty())
+}
+
+fn parse_lsblk(lsblk: &str) -> Vec<Disk> {
+    lsblk.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        let (name, size, kind) = (words.next()?, words.next()?, words.next()?);
+        (kind == "disk").then_some(())?;
+        Some(Disk { name: name.to_string(), size: size.parse().ok()? })
+    }).collect()
+}
+
+fn parse_lspci(lspci: &str) -> Vec<String> {
+    lspci.lines().filter_map(|line| {
+        // `01:00.0 VGA compatible controller: NVIDIA Corporation GP107 [GeForce GTX 1050] (rev a1)`
+        let (_, rest) = line.split_once(' ')?;
+        let (class, device) = rest.split_once(": ")?;
+        let is_gpu = class.starts_with("VGA") || class.starts_with("3D") || class.starts_with("Display");
+        is_gpu.then(|| device.trim().to_string())
+    }).collect()
+}
+
+impl HardwareInfo {
+    pub fn parse(raw: &RawInfo) -> HardwareInfo {
+        let threads = cpuinfo_values(&raw.cpuinfo, "processor").count() as u32;
+        let sockets = cpuinfo_values(&raw.cpuinfo, "physical id").collect::<BTreeSet<_>>().len().max(1) as u32;
+        let cores_per_socket = cpuinfo_values(&raw.cpuinfo, "cpu cores").next().and_then(|cores| cores.parse::<u32>().ok());
+        let max_mhz = raw.max_freq.as_deref().and_then(|khz| khz.trim().parse::<u32>().ok()).map(|khz| khz / 1000);
+        let current_mhz = cpuinfo_values(&raw.cpuinfo, "cpu MHz").next().and_then(|mhz| mhz.parse::<f64>().ok()).map(|mhz| mhz.round() as u32);
+
+        HardwareInfo {
+            cpu_model: cpuinfo_values(&raw.cpuinfo, "model name").next().map(String::from),
+            cores: cores_per_socket.map(|cores| cores * sockets),
+            threads: (threads > 0).then_some(threads),
+            cpu_mhz: max_mhz.or(current_mhz),
+            ram: meminfo_bytes(&raw.meminfo, "MemTotal"),
+            swap: meminfo_bytes(&raw.meminfo, "SwapTotal"),
+            nics: parse_nics(&raw.ip_addr),
+            kernel: raw.kernel.as_deref().map(str::trim).filter(|kernel| !kernel.is_empty()).map(String::from),
+            os: raw.os_release.as_deref().and_then(parse_os_release),
+            disks: raw.lsblk.as_deref().map(parse_lsblk).unwrap_or_default(),
+            gpus: raw.lspci.as_deref().map(parse_lspci).unwrap_or_default(),
+        }
+    }
+
+    /// MAC address of the first Ethernet interface
+    pub fn mac(&self) -> Option<&str> {
+        self.nics.iter().find_map(|nic| nic.mac.as_deref())
+    }
+
+    /// Bytes
+    pub fn disk_size(&self) -> u64 {
+        self.disks.iter().map(|disk| disk.size).sum()
+    }
+}
+
+/// Extended info as stored in JSON (journal, SQLite database), possibly before hardware was parsed at collection time
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredExtendedInfo {
+    Typed(Box<ExtendedInfo>),
+    Raw {
+        hostname: String,
+        cpuinfo: String,
+        meminfo: String,
+        ipaddr: String,
+    },
+}
+
+impl ExtendedInfo {
+    /// Parses extended info collected by older versions, keeping the raw text
+    pub fn from_legacy(hostname: String, cpuinfo: String, meminfo: String, ipaddr: String) -> ExtendedInfo {
+        let raw = RawInfo { cpuinfo, meminfo, ip_addr: ipaddr, ..RawInfo::default() };
+        ExtendedInfo {
+            hostname,
+            hardware: HardwareInfo::parse(&raw),
+            raw: Some(Box::new(raw)),
+            collected_at: 0,
+            first_collected_at: 0,
+        }
+    }
+}
+
+impl From<StoredExtendedInfo> for ExtendedInfo {
+    fn from(info: StoredExtendedInfo) -> Self {
+        match info {
+            StoredExtendedInfo::Typed(info) => *info,
+            StoredExtendedInfo::Raw { hostname, cpuinfo, meminfo, ipaddr } => ExtendedInfo::from_legacy(hostname, cpuinfo, meminfo, ipaddr),
+        }
+    }
+}
+
+/// Deserializes extended info in either format, for `#[serde(deserialize_with)]`
+pub fn deserialize_stored<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ExtendedInfo>, D::Error> {
+    Ok(Option::<StoredExtendedInfo>::deserialize(deserializer)?.map(ExtendedInfo::from))
+}
+
+pub fn stored_from_json(data: &str) -> Result<ExtendedInfo, serde_json::Error> {
+    serde_json::from_str::<StoredExtendedInfo>(data).map(ExtendedInfo::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Dual-socket machine with one core of two threads per socket, other cpuinfo fields left out
+    const CPUINFO: &str = "\
+processor\t: 0
+model name\t: Intel(R) Xeon(R) CPU E5-2620 v4 @ 2.10GHz
+cpu MHz\t\t: 1199.877
+physical id\t: 0
+cpu cores\t: 1
+
+processor\t: 1
+model name\t: Intel(R) Xeon(R) CPU E5-2620 v4 @ 2.10GHz
+cpu MHz\t\t: 1200.112
+physical id\t: 0
+cpu cores\t: 1
+
+processor\t: 2
+model name\t: Intel(R) Xeon(R) CPU E5-2620 v4 @ 2.10GHz
+cpu MHz\t\t: 1201.004
+physical id\t: 1
+cpu cores\t: 1
+
+processor\t: 3
+model name\t: Intel(R) Xeon(R) CPU E5-2620 v4 @ 2.10GHz
+cpu MHz\t\t: 1199.998
+physical id\t: 1
+cpu cores\t: 1
+";
+
+    const MEMINFO: &str = "\
+MemTotal:       16315508 kB
+MemFree:         9650208 kB
+MemAvailable:   13487124 kB
+SwapTotal:       2097148 kB
+SwapFree:        2097148 kB
+";
+
+    const IP_ADDR: &str = "\
+1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536 qdisc noqueue state UNKNOWN group default qlen 1000
+    link/loopback 00:00:00:00:00:00 brd 00:00:00:00:00:00
+    inet 127.0.0.1/8 scope host lo
+       valid_lft forever preferred_lft forever
+2: enp3s0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc fq_codel state UP group default qlen 1000
+    link/ether 3c:ec:ef:12:34:56 brd ff:ff:ff:ff:ff:ff
+    inet 172.29.4.12/16 brd 172.29.255.255 scope global dynamic enp3s0
+       valid_lft 85971sec preferred_lft 85971sec
+    inet6 fe80::3eec:efff:fe12:3456/64 scope link
+       valid_lft forever preferred_lft forever
+5: veth1a2b3c@if4: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue master docker0 state UP group default
+    link/ether 9a:1b:2c:3d:4e:5f brd ff:ff:ff:ff:ff:ff link-netnsid 0
+";
+
+    const LSPCI: &str = "\
+00:00.0 Host bridge: Intel Corporation Xeon E7 v4/Xeon E5 v4/Xeon E3 v4/Xeon D DMI2 (rev 01)
+00:1f.2 SATA controller: Intel Corporation C610/X99 series chipset 6-Port SATA Controller [AHCI mode] (rev 05)
+02:00.0 VGA compatible controller: NVIDIA Corporation GP107 [GeForce GTX 1050 Ti] (rev a1)
+02:00.1 Audio device: NVIDIA Corporation GP107GL High Definition Audio Controller (rev a1)
+08:00.0 VGA compatible controller: Matrox Electronics Systems Ltd. G200eR2 (rev 01)
+";
+
+    fn raw() -> RawInfo {
+        RawInfo {
+            cpuinfo: CPUINFO.to_string(),
+            meminfo: MEMINFO.to_string(),
+            ip_addr: IP_ADDR.to_string(),
+            kernel: Some(String::from("5.15.0-91-generic\n")),
+            os_release: Some(String::from("NAME=\"Ubuntu\"\nPRETTY_NAME=\"Ubuntu 22.04.3 LTS\"\nID=ubuntu\n")),
+            max_freq: Some(String::from("3000000\n")),
+            lsblk: Some(String::from("sda 500107862016 disk\nsr0 1073741312 rom\nnvme0n1 256060514304 disk\n")),
+            lspci: Some(LSPCI.to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_cpu_and_memory() {
+        let hardware = HardwareInfo::parse(&raw());
+        assert_eq!(hardware.cpu_model.as_deref(), Some("Intel(R) Xeon(R) CPU E5-2620 v4 @ 2.10GHz"));
+        assert_eq!((hardware.cores, hardware.threads), (Some(2), Some(4)));
+        assert_eq!(hardware.cpu_mhz, Some(3000));
+        assert_eq!(hardware.ram, Some(16_315_508_000));
+        assert_eq!(hardware.swap, Some(2_097_148_000));
+    }
+
+    #[test]
+    fn current_frequency_without_max() {
+        let raw = RawInfo { max_freq: None, ..raw() };
+        assert_eq!(HardwareInfo::parse(&raw).cpu_mhz, Some(1200));
+    }
+
+    #[test]
+    fn parses_ip_addr() {
+        let hardware = HardwareInfo::parse(&raw());
+        let nics: Vec<_> = hardware.nics.iter().map(|nic| (nic.name.as_str(), nic.mac.as_deref(), nic.ips.len())).collect();
+        assert_eq!(nics, [("lo", None, 1), ("enp3s0", Some("3c:ec:ef:12:34:56"), 2), ("veth1a2b3c", Some("9a:1b:2c:3d:4e:5f"), 0)]);
+        assert_eq!(hardware.nics[1].ips[0], "172.29.4.12/16");
+        assert_eq!(hardware.mac(), Some("3c:ec:ef:12:34:56"));
+    }
+
+    #[test]
+    fn parses_system_disks_and_gpus() {
+        let hardware = HardwareInfo::parse(&raw());
+        assert_eq!(hardware.kernel.as_deref(), Some("5.15.0-91-generic"));
+        assert_eq!(hardware.os.as_deref(), Some("Ubuntu 22.04.3 LTS"));
+        assert_eq!(hardware.disks.iter().map(|disk| disk.name.as_str()).collect::<Vec<_>>(), ["sda", "nvme0n1"]);
+        assert_eq!(hardware.disk_size(), 756_168_376_320);
+        assert_eq!(hardware.gpus, ["NVIDIA Corporation GP107 [GeForce GTX 1050 Ti] (rev a1)", "Matrox Electronics Systems Ltd. G200eR2 (rev 01)"]);
+    }
+
+    #[test]
+    fn missing_outputs_leave_fields_empty() {
+        let hardware = HardwareInfo::parse(&RawInfo::default());
+        assert_eq!((hardware.cpu_model, hardware.cores, hardware.threads, hardware.ram), (None, None, None, None));
+        assert!(hardware.nics.is_empty() && hardware.disks.is_empty() && hardware.gpus.is_empty());
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use serde::Serialize;
+use crate::{ExtendedInfo, MachineState, States};
+
+/// What makes a machine the same machine when its IP changes
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum Identity {
+    Mac(String),
+    /// When no network card was reported
+    Hostname(String),
+    /// When no extended info is known
+    Ip(Ipv4Addr),
+}
+
+impl Identity {
+    pub fn of(ip: Ipv4Addr, info: Option<&ExtendedInfo>) -> Identity {
+        let Some(info) = info else { return Identity::Ip(ip) };
+        match info.hardware.mac() {
+            Some(mac) => Identity::Mac(mac.to_lowercase()),
+            None if !info.hostname.is_empty() => Identity::Hostname(info.hostname.clone()),
+            None => Identity::Ip(ip),
+        }
+    }
+}
+
+impl std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identity::Mac(mac) => write!(f, "{mac}"),
+            Identity::Hostname(hostname) => write!(f, "{hostname}"),
+            Identity::Ip(ip) => write!(f, "unknown machine at {ip}"),
+        }
+    }
+}
+
+/// A period during which an IP was used by a machine
+#[derive(Debug, Clone, Serialize)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub identity: Identity,
+    /// 0 for the first lease of an IP, which covers its history before extended info was collected
+    pub from: u64,
+    /// None while the lease goes on
+    pub to: Option<u64>,
+}
+
+impl Lease {
+    fn covers(&self, time: u64) -> bool {
+        time >= self.from && self.to.is_none_or(|to| time < to)
+    }
+}
+
+/// Leases of an IP according to its own extended info snapshots only,
+/// with the time their machine was first seen there
+fn ip_leases(ip: Ipv4Addr, state: &MachineState) -> Vec<(Lease, u64)> {
+    let mut leases: Vec<(Lease, u64)> = Vec::new();
+    for info in state.snapshots() {
+        let identity = Identity::of(ip, Some(info));
+        match leases.last_mut() {
+            // A hardware change of the same machine
+            Some((last, _)) if last.identity == identity => continue,
+            Some((last, _)) => last.to = Some(info.first_collected_at),
+            None => (),
+        }
+        let from = match leases.is_empty() {
+            true => 0,
+            false => info.first_collected_at,
+        };
+        leases.push((Lease { ip, identity, from, to: None }, info.first_collected_at));
+    }
+    if leases.is_empty() {
+        leases.push((Lease { ip, identity: Identity::Ip(ip), from: 0, to: None }, 0));
+    }
+    leases
+}
+
+/// Lease timeline of every IP, oldest first.
+/// A machine seen at another IP has left the previous one, which is then used by an unknown machine until its next snapshot.
+fn leases(states: &States) -> BTreeMap<Ipv4Addr, Vec<Lease>> {
+    let mut arrivals: HashMap<Identity, Vec<(u64, Ipv4Addr)>> = HashMap::new();
+    for (ip, state) in states {
+        for info in state.snapshots().filter(|info| info.first_collected_at > 0) {
+            let identity = Identity::of(*ip, Some(info));
+            if !matches!(identity, Identity::Ip(_)) {
+                arrivals.entry(identity).or_default().push((info.first_collected_at, *ip));
+            }
+        }
+    }
+
+    let mut timelines = BTreeMap::new();
+    for (ip, state) in states {
+        let mut timeline = Vec::new();
+        for (mut lease, seen) in ip_leases(*ip, state) {
+            let moved = arrivals.get(&lease.identity)
+                .and_then(|arrivals| arrivals.iter().filter(|(time, other)| other != ip && *time > seen).map(|(time, _)| *time).min())
+                .filter(|moved| lease.covers(*moved));
+            match moved {
+                Some(moved) => {
+                    let end = lease.to.replace(moved);
+                    timeline.push(lease);
+                    timeline.push(Lease { ip: *ip, identity: Identity::Ip(*ip), from: moved, to: end });
+                },
+                None => timeline.push(lease),
+            }
+        }
+        timelines.insert(*ip, timeline);
+    }
+    timelines
+}
+
+/// Up periods of a state, the last one possibly still going on
+fn up_periods(changes: &[u64]) -> impl Iterator<Item = (u64, Option<u64>)> + '_ {
+    changes.chunks(2).map(|pair| (pair[0], pair.get(1).copied()))
+}
+
+/// A machine followed across the IPs it used
+pub struct TrackedMachine {
+    pub identity: Identity,
+    /// Oldest first
+    pub leases: Vec<Lease>,
+    /// Up periods of all leases merged, with the latest extended info of the machine
+    pub state: MachineState,
+}
+
+impl TrackedMachine {
+    /// IP of the latest lease
+    pub fn ip(&self) -> Ipv4Addr {
+        self.leases.iter().max_by_key(|lease| (lease.to.is_none(), lease.from)).map(|lease| lease.ip).unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+/// Machines followed across IPs, computed once per save and shared by the stats, the site, the predictor and the API
+#[derive(Default)]
+pub struct Fleet {
+    pub machines: Vec<TrackedMachine>,
+    /// Lease timeline of every IP, oldest first
+    pub leases: BTreeMap<Ipv4Addr, Vec<Lease>>,
+}
+
+/// Groups the history of all IPs by machine identity
+pub fn track(states: &States) -> Fleet {
+    let leases = leases(states);
+    let mut leases_by_identity: BTreeMap<Identity, Vec<Lease>> = BTreeMap::new();
+    for lease in leases.values().flatten() {
+        leases_by_identity.entry(lease.identity.clone()).or_default().push(lease.clone());
+    }
+
+    let machines = leases_by_identity.into_iter().map(|(identity, mut leases)| {
+        leases.sort_by_key(|lease| (lease.from, lease.ip));
+        let mut periods = Vec::new();
+        let mut state = MachineState::default();
+        for lease in &leases {
+            let Some(ip_state) = states.get(&lease.ip) else { continue };
+            for (start, end) in up_periods(&ip_state.changes) {
+                let clipped_start = start.max(lease.from);
+                let clipped_end = match (end, lease.to) {
+                    (Some(end), Some(to)) => Some(end.min(to)),
+                    (end, to) => end.or(to),
+                };
+                // Periods outside of the lease, but not machines first seen down within it
+                if clipped_end.is_some_and(|end| end < clipped_start || (end == clipped_start && start < lease.from)) {
+                    continue;
+                }
+                periods.push((clipped_start, clipped_end));
+            }
+            let last_checked = match lease.to {
+                Some(to) => ip_state.last_checked.min(to),
+                None => ip_state.last_checked,
+            };
+            state.last_checked = state.last_checked.max(last_checked);
+            if lease.to.is_none() {
+                state.last_probe = ip_state.last_probe.clone();
+                state.ssh_host = ip_state.ssh_host.clone();
+                state.load = ip_state.load.clone();
+            }
+            let latest_info = ip_state.snapshots().filter(|info| Identity::of(lease.ip, Some(info)) == identity).last();
+            if latest_info.is_some_and(|info| state.extended_info.as_ref().is_none_or(|current| info.first_collected_at >= current.first_collected_at)) {
+                state.extended_info = latest_info.cloned();
+            }
+        }
+
+        // Union of the periods, which overlap if the machine answered at two IPs at once
+        periods.sort();
+        let mut merged: Vec<(u64, Option<u64>)> = Vec::new();
+        for (start, end) in periods {
+            match merged.last_mut() {
+                Some((_, last_end)) if last_end.is_none_or(|last_end| start <= last_end) => {
+                    *last_end = match (*last_end, end) {
+                        (Some(last_end), Some(end)) => Some(last_end.max(end)),
+                        _ => None,
+                    };
+                },
+                _ => merged.push((start, end)),
+            }
+        }
+        state.changes = merged.into_iter().flat_map(|(start, end)| std::iter::once(start).chain(end)).collect();
+
+        TrackedMachine { identity, leases, state }
+    }).collect();
+    Fleet { machines, leases }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{HardwareInfo, Nic};
+
+    const FIRST: Ipv4Addr = Ipv4Addr::new(172, 29, 4, 12);
+    const SECOND: Ipv4Addr = Ipv4Addr::new(172, 29, 4, 13);
+
+    fn info(hostname: &str, mac: Option<&str>, collected_at: u64) -> ExtendedInfo {
+        let nic = Nic { name: String::from("eth0"), mac: mac.map(String::from), ips: Vec::new() };
+        ExtendedInfo {
+            hostname: hostname.to_string(),
+            hardware: HardwareInfo { nics: vec![nic], ..Default::default() },
+            raw: None,
+            collected_at,
+            first_collected_at: 0,
+        }
+    }
+
+    fn machine<'a>(fleet: &'a Fleet, identity: &Identity) -> &'a TrackedMachine {
+        fleet.machines.iter().find(|machine| machine.identity == *identity).expect("machine is tracked")
+    }
+
+    #[test]
+    fn mac_move_merges_history() {
+        let mut states = States::new();
+        let first = states.entry(FIRST).or_default();
+        first.checked(true, 100);
+        first.record_info(info("iftpc12", Some("AA:BB:CC:DD:EE:FF"), 100));
+        first.checked(false, 500);
+        // Another machine uses the IP after the move, no info was collected there since
+        first.checked(true, 2000);
+        let second = states.entry(SECOND).or_default();
+        second.checked(true, 1000);
+        second.record_info(info("iftpc12", Some("aa:bb:cc:dd:ee:ff"), 1000));
+
+        let fleet = track(&states);
+        assert_eq!(fleet.machines.len(), 2);
+        let moved = machine(&fleet, &Identity::Mac(String::from("aa:bb:cc:dd:ee:ff")));
+        assert_eq!(moved.leases.iter().map(|lease| (lease.ip, lease.from, lease.to)).collect::<Vec<_>>(), [(FIRST, 0, Some(1000)), (SECOND, 0, None)]);
+        assert_eq!(moved.ip(), SECOND);
+        assert_eq!(moved.state.changes, [100, 500, 1000]);
+        let unknown = machine(&fleet, &Identity::Ip(FIRST));
+        assert_eq!(unknown.ip(), FIRST);
+        assert_eq!(unknown.state.changes, [2000]);
+        assert_eq!(fleet.leases[&FIRST].iter().map(|lease| lease.identity.clone()).collect::<Vec<_>>(), [moved.identity.clone(), Identity::Ip(FIRST)]);
+    }
+
+    #[test]
+    fn hostname_matches_without_mac() {
+        let mut states = States::new();
+        let first = states.entry(FIRST).or_default();
+        first.checked(true, 100);
+        first.record_info(info("iftpc12", None, 100));
+        first.checked(false, 500);
+        let second = states.entry(SECOND).or_default();
+        second.checked(true, 1000);
+        second.record_info(info("iftpc12", None, 1000));
+
+        let fleet = track(&states);
+        assert_eq!(fleet.machines.len(), 2);
+        let moved = machine(&fleet, &Identity::Hostname(String::from("iftpc12")));
+        assert_eq!(moved.leases.iter().map(|lease| lease.ip).collect::<Vec<_>>(), [FIRST, SECOND]);
+        assert_eq!(moved.ip(), SECOND);
+        assert_eq!(moved.state.changes, [100, 500, 1000]);
+        assert_eq!(machine(&fleet, &Identity::Ip(FIRST)).leases[0].from, 1000);
+    }
+
+    #[test]
+    fn lease_ends_when_another_machine_is_seen() {
+        let mut states = States::new();
+        let state = states.entry(FIRST).or_default();
+        state.checked(true, 50);
+        state.record_info(info("iftpc12", Some("aa:aa:aa:aa:aa:aa"), 100));
+        state.checked(false, 300);
+        state.checked(true, 600);
+        state.record_info(info("iftpc13", Some("bb:bb:bb:bb:bb:bb"), 500));
+
+        let fleet = track(&states);
+        let leases = &fleet.leases[&FIRST];
+        assert_eq!(leases.iter().map(|lease| (lease.from, lease.to)).collect::<Vec<_>>(), [(0, Some(500)), (500, None)]);
+        assert!(leases[0].covers(0) && leases[0].covers(499) && !leases[0].covers(500));
+        assert!(leases[1].covers(500) && leases[1].covers(u64::MAX));
+        assert_eq!(machine(&fleet, &Identity::Mac(String::from("aa:aa:aa:aa:aa:aa"))).state.changes, [50, 300]);
+        assert_eq!(machine(&fleet, &Identity::Mac(String::from("bb:bb:bb:bb:bb:bb"))).state.changes, [600]);
+    }
+}
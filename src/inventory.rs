@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use serde::Serialize;
+use crate::{ExtendedInfo, MachineState, States};
+
+/// Older snapshots are forgotten past this number
+const MAX_SNAPSHOTS: usize = 10;
+
+/// Kernel updates move the total memory by a few megabytes, which isn't a hardware change
+const RAM_TOLERANCE: f64 = 0.02;
+
+/// A difference between two snapshots of the extended info of a machine
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldChange {
+    /// `hostname`, `cpu`, `ram` or `mac`
+    pub field: &'static str,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let before = self.before.as_deref().unwrap_or("unknown");
+        let after = self.after.as_deref().unwrap_or("unknown");
+        write!(f, "{} {before} -> {after}", self.field)
+    }
+}
+
+fn ram_gb(ram: Option<u64>) -> Option<String> {
+    ram.map(|ram| format!("{:.1} GB", ram as f64 / 1_000_000_000.0))
+}
+
+/// What changed from `old` to `new`, among the hostname, CPU model, RAM and MAC address
+pub fn diff(old: &ExtendedInfo, new: &ExtendedInfo) -> Vec<FieldChange> {
+    let change = |field, before: Option<String>, after: Option<String>| (before != after).then_some(FieldChange { field, before, after });
+    let ram_changed = match (old.hardware.ram, new.hardware.ram) {
+        (Some(before), Some(after)) => before.abs_diff(after) as f64 > before.max(after) as f64 * RAM_TOLERANCE,
+        (before, after) => before != after,
+    };
+    [
+        change("hostname", Some(old.hostname.clone()), Some(new.hostname.clone())),
+        change("cpu", old.hardware.cpu_model.clone(), new.hardware.cpu_model.clone()),
+        ram_changed.then(|| FieldChange { field: "ram", before: ram_gb(old.hardware.ram), after: ram_gb(new.hardware.ram) }),
+        change("mac", old.hardware.mac().map(String::from), new.hardware.mac().map(String::from)),
+    ].into_iter().flatten().collect()
+}
+
+impl MachineState {
+    /// Replaces the extended info with a newly collected one, returning what changed.
+    /// The previous info goes to the history when anything did.
+    pub fn record_info(&mut self, mut info: ExtendedInfo) -> Vec<FieldChange> {
+        if info.first_collected_at == 0 {
+            info.first_collected_at = info.collected_at;
+        }
+        let Some(previous) = self.extended_info.take() else {
+            self.extended_info = Some(info);
+            return Vec::new();
+        };
+        let changes = diff(&previous, &info);
+        if changes.is_empty() && previous.first_collected_at > 0 {
+            info.first_collected_at = previous.first_collected_at;
+        } else if !changes.is_empty() {
+            self.info_history.push(previous);
+            if self.info_history.len() > MAX_SNAPSHOTS {
+                self.info_history.remove(0);
+            }
+        }
+        self.extended_info = Some(info);
+        changes
+    }
+
+    /// Distinct snapshots of the extended info, oldest first, ending with the current one
+    pub fn snapshots(&self) -> impl Iterator<Item = &ExtendedInfo> {
+        self.info_history.iter().chain(self.extended_info.as_ref())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reassignment {
+    /// A network card was seen at an IP, then at another one
+    MacMoved {
+        time: u64,
+        mac: String,
+        hostname: String,
+        from: Ipv4Addr,
+        to: Ipv4Addr,
+    },
+    /// An IP was used by a machine, then by another one
+    HostnameChanged {
+        time: u64,
+        ip: Ipv4Addr,
+        from: String,
+        to: String,
+    },
+}
+
+impl Reassignment {
+    pub fn time(&self) -> u64 {
+        match self {
+            Reassignment::MacMoved { time, .. } | Reassignment::HostnameChanged { time, .. } => *time,
+        }
+    }
+}
+
+impl std::fmt::Display for Reassignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reassignment::MacMoved { mac, hostname, from, to, .. } => write!(f, "{mac} ({hostname}) moved from {from} to {to}"),
+            Reassignment::HostnameChanged { ip, from, to, .. } => write!(f, "{ip} changed from {from} to {to}"),
+        }
+    }
+}
+
+/// IP reassignments found in the extended info history of all machines, in chronological order.
+/// Each snapshot counts from the time it was first collected, snapshots of unknown time are left out.
+pub fn reassignments(states: &States, since: u64) -> Vec<Reassignment> {
+    let mut reassignments = Vec::new();
+    let mut sightings: HashMap<&str, Vec<(u64, Ipv4Addr, &str)>> = HashMap::new();
+    for (ip, state) in states {
+        let mut previous: Option<&ExtendedInfo> = None;
+        for info in state.snapshots().filter(|info| info.first_collected_at > 0) {
+            if let Some(mac) = info.hardware.mac() {
+                sightings.entry(mac).or_default().push((info.first_collected_at, *ip, info.hostname.as_str()));
+            }
+            if let Some(previous) = previous.filter(|previous| previous.hostname != info.hostname) {
+                reassignments.push(Reassignment::HostnameChanged {
+                    time: info.first_collected_at,
+                    ip: *ip,
+                    from: previous.hostname.clone(),
+                    to: info.hostname.clone(),
+                });
+            }
+            previous = Some(info);
+        }
+    }
+    for (mac, mut sightings) in sightings {
+        sightings.sort();
+        for pair in sightings.windows(2) {
+            let ((_, from, _), (time, to, hostname)) = (pair[0], pair[1]);
+            if from != to {
+                reassignments.push(Reassignment::MacMoved { time, mac: mac.to_string(), hostname: hostname.to_string(), from, to });
+            }
+        }
+    }
+    reassignments.retain(|reassignment| reassignment.time() >= since);
+    reassignments.sort_by_key(|reassignment| reassignment.time());
+    reassignments
+}
@@ -0,0 +1,181 @@
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use crate::{ExtendedInfo, States};
+use crate::error::Error;
+use crate::hardware::deserialize_stored;
+use crate::load::LoadSample;
+use crate::probe::ProbeDetails;
+use crate::ssh::HostKeyScan;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The machine was found up or down while previously in the other state (or never seen before).
+    /// Events written when the journal is created from existing states have no probe details.
+    State {
+        time: u64,
+        ip: Ipv4Addr,
+        up: bool,
+        probe: Option<ProbeDetails>,
+    },
+    /// Extended info was loaded (or failed to load, in which case `info` is empty and the previous info still applies)
+    ExtendedInfo {
+        time: u64,
+        ip: Ipv4Addr,
+        #[serde(deserialize_with = "deserialize_stored")]
+        info: Option<ExtendedInfo>,
+        error: Option<String>,
+    },
+    /// The SSH server presented its host key, which may be a new one
+    HostKey {
+        time: u64,
+        ip: Ipv4Addr,
+        scan: HostKeyScan,
+    },
+    /// The load of a machine was sampled
+    Load {
+        ip: Ipv4Addr,
+        #[serde(flatten)]
+        sample: LoadSample,
+    },
+}
+
+/// Append-only log of events, one JSON object per line.
+/// Replaying it from the start rebuilds the states, so states.bin is only a cache of it.
+pub struct Journal {
+    file: Option<(PathBuf, tokio::fs::File)>,
+}
+
+impl Journal {
+    pub fn disabled() -> Journal {
+        Journal { file: None }
+    }
+
+    /// Opens the journal for appending.
+    /// When it doesn't exist yet, it is seeded with the history already in `states` so that it stays complete.
+    pub async fn open(path: &Path, states: &States) -> Result<Journal, Error> {
+        let exists = tokio::fs::try_exists(path).await.map_err(|e| Error::io(path, e))?;
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| Error::io(path, e))?;
+        let mut journal = Journal { file: Some((path.to_path_buf(), file)) };
+
+        if !exists {
+            for (ip, state) in states {
+                // A machine first seen down starts with two identical changes, which replaying a single down state recreates
+                let first_seen_down = state.changes.len() >= 2 && state.changes[0] == state.changes[1];
+                for (i, time) in state.changes.iter().enumerate() {
+                    let up = match (i, first_seen_down) {
+                        (0, true) => false,
+                        (1, true) => continue,
+                        _ => i % 2 == 0,
+                    };
+                    let probe = state.last_probe.clone().filter(|_| i + 1 == state.changes.len());
+                    journal.append(&Event::State { time: *time, ip: *ip, up, probe }).await?;
+                }
+                for info in state.snapshots() {
+                    let time = match info.collected_at {
+                        0 => state.last_checked,
+                        collected_at => collected_at,
+                    };
+                    journal.append(&Event::ExtendedInfo { time, ip: *ip, info: Some(info.clone()), error: None }).await?;
+                }
+                if let Some((time, error)) = &state.last_info_error {
+                    journal.append(&Event::ExtendedInfo { time: *time, ip: *ip, info: None, error: Some(error.clone()) }).await?;
+                }
+                if let Some(ssh) = &state.ssh_host {
+                    for key in &ssh.keys {
+                        let scan = HostKeyScan { banner: ssh.banner.clone(), key_type: key.key_type.clone(), fingerprint: key.fingerprint.clone() };
+                        journal.append(&Event::HostKey { time: key.first_seen, ip: *ip, scan: scan.clone() }).await?;
+                        if key.last_seen != key.first_seen {
+                            journal.append(&Event::HostKey { time: key.last_seen, ip: *ip, scan }).await?;
+                        }
+                    }
+                }
+                for sample in &state.load {
+                    journal.append(&Event::Load { ip: *ip, sample: sample.clone() }).await?;
+                }
+            }
+            journal.sync().await?;
+        }
+
+        Ok(journal)
+    }
+
+    pub async fn append(&mut self, event: &Event) -> Result<(), Error> {
+        let Some((path, file)) = &mut self.file else { return Ok(()) };
+        let mut line = serde_json::to_string(event).map_err(|e| Error::Format(format!("Failed to serialize event: {e}")))?;
+        line.push('\n');
+        file.write_all(line.as_bytes()).await.map_err(|e| Error::io(path, e))
+    }
+
+    pub async fn sync(&mut self) -> Result<(), Error> {
+        let Some((path, file)) = &mut self.file else { return Ok(()) };
+        file.sync_data().await.map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Rebuilds states from a journal
+pub async fn replay(path: &Path) -> Result<States, Error> {
+    let file = tokio::fs::File::open(path).await.map_err(|e| Error::io(path, e))?;
+    let mut lines = BufReader::new(file).lines();
+    let mut states = States::new();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await.map_err(|e| Error::io(path, e))? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Event = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            // A crash can leave a line incomplete
+            Err(e) if e.is_eof() => {
+                eprintln!("Skipping incomplete event on line {line_number} of journal");
+                continue;
+            }
+            Err(e) => return Err(Error::Format(format!("Invalid event on line {line_number} of journal: {e}"))),
+        };
+        match event {
+            Event::State { time, ip, up, probe } => {
+                let state = states.entry(ip).or_default();
+                state.checked(up, time);
+                // Journals written before probes were recorded don't have it
+                if probe.is_some() {
+                    state.last_probe = probe;
+                }
+            }
+            Event::ExtendedInfo { time, ip, info, error } => {
+                let state = states.entry(ip).or_default();
+                match (info, error) {
+                    (Some(mut info), _) => {
+                        if info.collected_at == 0 {
+                            info.collected_at = time;
+                        }
+                        state.record_info(info);
+                        state.last_info_error = None;
+                    }
+                    // Failures don't discard previously collected info
+                    (None, Some(error)) => state.last_info_error = Some((time, error)),
+                    (None, None) => state.extended_info = None,
+                }
+                state.last_checked = state.last_checked.max(time);
+            }
+            Event::HostKey { time, ip, scan } => {
+                let state = states.entry(ip).or_default();
+                state.ssh_host.get_or_insert_default().observe(scan, time);
+                state.last_checked = state.last_checked.max(time);
+            }
+            Event::Load { ip, sample } => {
+                let state = states.entry(ip).or_default();
+                state.last_checked = state.last_checked.max(sample.time);
+                state.add_load_sample(sample);
+            }
+        }
+    }
+    Ok(states)
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeSet;
+use serde::{Serialize, Deserialize};
+use crate::MachineState;
+use crate::hardware::meminfo_bytes;
+
+/// How busy a machine was at some point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadSample {
+    pub time: u64,
+    /// Load averages over 1, 5 and 15 minutes
+    pub load: [f32; 3],
+    /// Share of CPU time spent working over one second, between 0 and 1
+    pub cpu_usage: Option<f32>,
+    /// Bytes of memory available for new programs
+    pub mem_available: Option<u64>,
+    /// Distinct users with a session
+    pub users: Option<u32>,
+}
+
+/// Command outputs a sample is parsed from
+pub struct RawLoad {
+    /// `cat /proc/loadavg`
+    pub loadavg: String,
+    /// `cat /proc/meminfo`
+    pub meminfo: Option<String>,
+    /// First line of /proc/stat, twice with a second in between
+    pub stat: Option<String>,
+    /// `who`
+    pub who: Option<String>,
+}
+
+/// Older samples are forgotten past this number
+pub const MAX_SAMPLES: usize = 144;
+
+/// Working and total jiffies of a `cpu` line of /proc/stat
+fn cpu_times(line: &str) -> Option<(u64, u64)> {
+    let mut words = line.split_whitespace();
+    (words.next()? == "cpu").then_some(())?;
+    // user nice system idle iowait irq softirq steal (guest time is already counted in user)
+    let times = words.take(8).map(|time| time.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+    let total = times.iter().sum::<u64>();
+    let idle = times.get(3)? + times.get(4).unwrap_or(&0);
+    Some((total - idle, total))
+}
+
+fn parse_cpu_usage(stat: &str) -> Option<f32> {
+    let mut lines = stat.lines().filter_map(cpu_times);
+    let ((busy1, total1), (busy2, total2)) = (lines.next()?, lines.next()?);
+    let total = total2.checked_sub(total1).filter(|total| *total > 0)?;
+    Some((busy2.saturating_sub(busy1) as f32 / total as f32).clamp(0.0, 1.0))
+}
+
+fn parse_users(who: &str) -> u32 {
+    who.lines().filter_map(|line| line.split_whitespace().next()).collect::<BTreeSet<_>>().len() as u32
+}
+
+impl LoadSample {
+    pub fn parse(raw: &RawLoad, time: u64) -> Option<LoadSample> {
+        let mut averages = raw.loadavg.split_whitespace().map(|load| load.parse::<f32>().ok());
+        let load = [averages.next()??, averages.next()??, averages.next()??];
+        let meminfo = raw.meminfo.as_deref();
+        Some(LoadSample {
+            time,
+            load,
+            cpu_usage: raw.stat.as_deref().and_then(parse_cpu_usage),
+            // MemAvailable is missing before Linux 3.14
+            mem_available: meminfo.and_then(|meminfo| meminfo_bytes(meminfo, "MemAvailable").or_else(|| meminfo_bytes(meminfo, "MemFree"))),
+            users: raw.who.as_deref().map(parse_users),
+        })
+    }
+
+    /// 1-minute load average per hardware thread, 1 meaning all of them are busy
+    pub fn load_per_thread(&self, threads: Option<u32>) -> f32 {
+        self.load[0] / threads.filter(|threads| *threads > 0).unwrap_or(1) as f32
+    }
+}
+
+impl MachineState {
+    pub fn add_load_sample(&mut self, sample: LoadSample) {
+        self.load.push(sample);
+        if self.load.len() > MAX_SAMPLES {
+            self.load.remove(0);
+        }
+    }
+
+    /// Latest sample, if it was taken since the machine came up
+    pub fn current_load(&self) -> Option<&LoadSample> {
+        self.load.last().filter(|sample| self.up() && sample.time >= self.last_change())
+    }
+
+    /// Whether a new sample should be taken if the machine is up.
+    /// Checks of machines that are up come a bit early because of the jitter of the schedule,
+    /// and a sample is taken a bit before its check ends, so a tenth of the interval is tolerated.
+    pub fn load_sample_due(&self, now_utc: u64, interval_secs: u64) -> bool {
+        match self.current_load() {
+            Some(sample) => now_utc.saturating_sub(sample.time) >= interval_secs.saturating_sub(interval_secs / 10),
+            None => true,
+        }
+    }
+
+    /// How loaded the machine currently is, to sort machines by.
+    /// Machines without a recent sample come last.
+    pub fn load_score(&self) -> f32 {
+        let threads = self.extended_info.as_ref().and_then(|info| info.hardware.threads);
+        self.current_load().map(|sample| sample.load_per_thread(threads)).unwrap_or(f32::INFINITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled_at(time: u64) -> MachineState {
+        let mut state = MachineState::default();
+        state.checked(true, 0);
+        state.add_load_sample(LoadSample { time, load: [0.5, 0.4, 0.3], cpu_usage: None, mem_available: None, users: None });
+        state
+    }
+
+    #[test]
+    fn sample_due_on_jittered_check() {
+        // Sampled 2s before the check ended at 1000, next check 10% early
+        let state = sampled_at(998);
+        assert!(state.load_sample_due(1000 + 540, 600));
+        assert!(!state.load_sample_due(1000 + 300, 600));
+    }
+
+    #[test]
+    fn sample_due_without_sample() {
+        let mut state = MachineState::default();
+        state.checked(true, 0);
+        assert!(state.load_sample_due(10, 600));
+    }
+}
@@ -1,46 +1,56 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::time::{Instant, Duration};
-use futures::future::select_all;
-use string_tools::{get_all_before_strict, get_all_after_strict, get_all_between_strict};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::time::{sleep, timeout};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use string_tools::get_all_between_strict;
+use tokio::sync::{RwLock, Semaphore};
 use progress_bar::{global::*, Color, Style};
 use serde::{Serialize, Deserialize};
+use clap::Parser;
+use cli::{Args, Command};
+use config::Config;
+use error::{with_retries, Error};
+use hardware::{HardwareInfo, RawInfo};
+use identity::{Fleet, Identity, Lease};
+use journal::{Event, Journal};
+use load::{LoadSample, RawLoad};
+use metrics::METRICS;
+use politeness::{Politeness, RateLimiter, Throttling};
+use prediction::Predictor;
+use refresh::{refresh_reason, RefreshReason};
+use rooms::room_of;
+use probe::{ProbeDetails, Probes};
+use shutdown::Shutdown;
+use ssh::{HostKeyScan, SshClient, SshError, SshHost};
+use storage::{open_storage, Storage};
 
-// IPs are updated on an hourly basis
-// The hour is divided into 6 parts.
-// Machines we think are on are checked at each one.
-// The rest of them only get checked once every hour.
-
-pub async fn run_shell_command(command: impl AsRef<str>) -> Result<String, String> {
-    let command = command.as_ref();
-    let output = tokio::process::Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .output()
-        .await
-        .expect("failed to execute process");
-    let mut stdouterr = String::from_utf8_lossy(&output.stdout).into_owned();
-    stdouterr.push_str(String::from_utf8_lossy(&output.stderr).as_ref());
-    if output.status.success() {
-        Ok(stdouterr)
-    } else {
-        Err(stdouterr)
-    }
-}
-
-fn generate_ips() -> HashSet<Ipv4Addr> {
-    let mut ips = HashSet::new();
-    for i in 0..=255 {
-        for j in 0..=255 {
-            let ip = Ipv4Addr::new(172, 29, i, j);
-            ips.insert(ip);
-        }
-    }
-    ips
-}
+mod api;
+mod cli;
+mod config;
+mod error;
+mod hardware;
+mod identity;
+mod inventory;
+mod journal;
+mod load;
+mod metrics;
+mod migrations;
+mod persistence;
+mod politeness;
+mod prediction;
+mod probe;
+mod recommend;
+mod refresh;
+mod rooms;
+mod schedule;
+mod shutdown;
+mod sqlite;
+mod ssh;
+mod storage;
+mod targets;
 
 type States = HashMap<Ipv4Addr, MachineState>;
 
@@ -48,93 +58,380 @@ fn now_utc() -> u64 {
     chrono::Utc::now().timestamp() as u64
 }
 
-async fn restore_state(data_dir: &str) -> States {
-    let file: Vec<u8> = match tokio::fs::read(format!("{data_dir}/states.bin")).await {
-        Ok(file) => file,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return States::new(),
-        Err(e) => panic!("Failed to open states.bin: {}", e),
-    };
-
-    bincode::deserialize_from(file.as_slice()).expect("Failed to deserialize states.bin")
+/// A machine to check
+struct Candidate {
+    ip: Ipv4Addr,
+    was_up: bool,
+    has_host_key: bool,
+    /// Collect extended info even if the machine was already up
+    refresh: Option<RefreshReason>,
+    /// Sample its load if it is up
+    sample_load: bool,
 }
 
-async fn save_states(states: &States, data_dir: &str) {
-    let file = bincode::serialize(states).expect("Failed to serialize states");
-    tokio::fs::write(format!("{data_dir}/states.bin"), file).await.expect("Failed to write states.bin");
+/// What was learned about a machine during a check
+struct CheckResult {
+    ip: Ipv4Addr,
+    up: bool,
+    probe: ProbeDetails,
+    extended_info: Option<Result<ExtendedInfo, SshError>>,
+    host_key: Option<Result<HostKeyScan, SshError>>,
+    load: Option<Result<LoadSample, SshError>>,
 }
 
-async fn check_ip(ip: Ipv4Addr, was_up: bool, data_dir: &str, username: &Option<String>) -> (Ipv4Addr, bool, Option<Result<ExtendedInfo, String>>) {
-    let time_to_wait = match was_up {
-        true => Duration::from_secs(12),
-        false => Duration::from_secs(4),
+async fn check_ip(candidate: Candidate, probes: &Probes, ssh_slots: &Semaphore, limiter: &RateLimiter, config: &Config) -> CheckResult {
+    let Candidate { ip, was_up, has_host_key, refresh, sample_load, .. } = candidate;
+    let probe = probes.for_ip(ip).probe(ip, config.probe.timeout(was_up)).await;
+    let up = probe.up();
+    METRICS.probe_duration.observe(Duration::from_millis(probe.elapsed_ms));
+    match up {
+        true => METRICS.probes_up.fetch_add(1, Ordering::Relaxed),
+        false => METRICS.probes_down.fetch_add(1, Ordering::Relaxed),
     };
-    let r = timeout(time_to_wait, async move {
-        TcpStream::connect(
-            &std::net::SocketAddr::new(std::net::IpAddr::V4(ip), 22)
-        ).await.is_ok()
-    }).await;
-    let up = r == Ok(true);
-    let extended_info = if !was_up && up {
-        if let Some(username) = username {
-            Some(load_extented_info(ip, data_dir, username).await)
-        } else {
-            None
+
+    let collect_info = up && (!was_up || refresh.is_some());
+    let sample_load = up && sample_load;
+    let username = config.username.as_deref().filter(|_| collect_info || sample_load);
+    let scan_host_key = up && (!was_up || !has_host_key) && config.ssh.scan_host_keys;
+    if username.is_none() && !scan_host_key {
+        return CheckResult { ip, up, probe, extended_info: None, host_key: None, load: None };
+    }
+
+    // The same connection gives the host key, the extended info and the load
+    let _slot = ssh_slots.acquire().await.expect("SSH semaphore closed");
+    limiter.acquire().await;
+    let time_to_wait = Duration::from_secs(config.ssh.timeout_secs);
+    let client = SshClient::connect(ip, config.ssh.port, time_to_wait).await;
+    let host_key = scan_host_key.then(|| client.as_ref().map_err(Clone::clone).and_then(|client| client.host_key()));
+    let login = match (username, &client) {
+        (Some(username), Ok(client)) => {
+            let private_key = std::path::Path::new(&config.data_dir).join("ssh-key");
+            Some(client.authenticate(username, &private_key).await.map(|_| client))
+        },
+        (Some(_), Err(e)) => Some(Err(e.clone())),
+        (None, _) => None,
+    };
+    let mut extended_info = None;
+    let mut load = None;
+    if let Some(login) = login {
+        if collect_info {
+            let result = match &login {
+                Ok(client) => load_extented_info(client, config).await,
+                Err(e) => Err(e.clone()),
+            };
+            extended_info = Some(result);
         }
-    } else {
-        None
+        if sample_load {
+            let result = match &login {
+                Ok(client) => load_sample(client, time_to_wait).await,
+                Err(e) => Err(e.clone()),
+            };
+            load = Some(result);
+        }
+    }
+    match &extended_info {
+        Some(Ok(_)) => METRICS.extended_info_successes.fetch_add(1, Ordering::Relaxed),
+        Some(Err(_)) => METRICS.extended_info_failures.fetch_add(1, Ordering::Relaxed),
+        None => 0,
     };
-    (ip, up, extended_info)
+    if let Ok(client) = client {
+        client.disconnect().await;
+    }
+    CheckResult { ip, up, probe, extended_info, host_key, load }
+}
+
+/// Records the outcome of a check in the states, the journal and the storage
+async fn record_result(result: CheckResult, states: &RwLock<States>, storage: &mut dyn Storage, journal: &mut Journal, config: &Config) -> schedule::Deadline {
+    let CheckResult { ip, up, probe, extended_info, host_key, load } = result;
+    let now_utc = now_utc();
+    let mut states = states.write().await;
+    let state = states.entry(ip).or_default();
+    let previous_changes = state.changes.len();
+    let extended_info_updated = matches!(extended_info, Some(Ok(_)));
+    let mut events = Vec::new();
+    match extended_info {
+        Some(Ok(mut extended_info)) => {
+            extended_info.collected_at = now_utc;
+            events.push(Event::ExtendedInfo { time: now_utc, ip, info: Some(extended_info.clone()), error: None });
+            let changes = state.record_info(extended_info);
+            if !changes.is_empty() {
+                let changes = changes.iter().map(|change| change.to_string()).collect::<Vec<_>>().join(", ");
+                print_progress_bar_info("Changed", &format!("extended info of {ip}: {changes}"), Color::Yellow, Style::Bold);
+            }
+            state.last_info_error = None;
+        },
+        Some(Err(err)) => {
+            // Previously collected info is kept, it's most likely still right
+            print_progress_bar_info("Failed", &format!("to load extended info for {ip}: {err}"), Color::Yellow, Style::Bold);
+            events.push(Event::ExtendedInfo { time: now_utc, ip, info: None, error: Some(err.to_string()) });
+            state.last_info_error = Some((now_utc, err.to_string()));
+        },
+        None => (),
+    }
+    state.checked(up, now_utc);
+    if state.changes.len() != previous_changes {
+        events.insert(0, Event::State { time: now_utc, ip, up, probe: Some(probe.clone()) });
+    }
+    state.last_probe = Some(probe);
+    match host_key {
+        Some(Ok(scan)) => {
+            events.push(Event::HostKey { time: now_utc, ip, scan: scan.clone() });
+            if let Some(previous) = state.ssh_host.get_or_insert_default().observe(scan, now_utc) {
+                print_progress_bar_info("Changed", &format!("host key of {ip} (was {} {})", previous.key_type, previous.fingerprint), Color::Yellow, Style::Bold);
+            }
+        },
+        Some(Err(err)) => print_progress_bar_info("Failed", &format!("to scan host key of {ip}: {err}"), Color::Yellow, Style::Bold),
+        None => (),
+    }
+    let load_sampled = matches!(load, Some(Ok(_)));
+    match load {
+        Some(Ok(sample)) => {
+            events.push(Event::Load { ip, sample: sample.clone() });
+            state.add_load_sample(sample);
+        },
+        Some(Err(err)) => print_progress_bar_info("Failed", &format!("to sample load of {ip}: {err}"), Color::Yellow, Style::Bold),
+        None => (),
+    }
+    // Readers, like the API, don't have to wait for the journal and storage
+    let states = states.downgrade();
+    let state = &states[&ip];
+    for event in &events {
+        if let Err(e) = journal.append(event).await {
+            print_progress_bar_info("Failed", &format!("to write to journal: {e}"), Color::Red, Style::Bold);
+        }
+    }
+    if let Err(e) = storage.record(ip, state, previous_changes, extended_info_updated, load_sampled).await {
+        print_progress_bar_info("Failed", &format!("to record state of {ip}: {e}"), Color::Red, Style::Bold);
+    }
+    schedule::deadline(ip, state, now_utc, &config.schedule)
 }
 
-async fn update(states: &mut States, data_dir: &str, username: &Option<String>) {
-    let mut candidates: Vec<(Ipv4Addr, bool, u64)> = states.iter().map(|(ip, state)| {
-        (*ip, state.has_ever_been_up(), state.last_checked())
-    }).collect();
-    candidates.sort_by(|(_, up1, t1), (_, up2, t2)| {
-        up1.cmp(up2).reverse().then(t1.cmp(t2))
-    });
-    candidates.truncate((255*255)/6);
-    candidates.reverse();
-    init_progress_bar_with_eta(candidates.len());
+/// Checks machines as they become due, with at most `scan.concurrency` checks in flight,
+/// within the limits of the `[politeness]` section.
+/// With `once`, only the machines due at the start are checked, then it returns.
+/// When shutdown is requested, no more checks start and it returns once the checks in flight are over,
+/// or when `scan.shutdown_timeout_secs` have passed.
+/// Machines are followed across IPs again on each save, and `fleet` replaced with the result.
+async fn scan(states: &RwLock<States>, fleet: &RwLock<Fleet>, storage: &mut dyn Storage, journal: &mut Journal, config: &Config, once: bool, shutdown: &mut Shutdown) -> Throttling {
+    let data_dir = config.data_dir.as_str();
+    let mut queue = schedule::Queue::new(&*states.read().await, now_utc(), &config.schedule);
+    queue.promote(now_utc());
+    let probes = Probes::new(&config.probe.method, &config.probe.rules);
+    let ssh_slots = Semaphore::new(config.ssh.concurrency);
+    let mut politeness = Politeness::new(config);
+    let limiter = politeness.limiter().clone();
+    let period = Duration::from_secs(config.scan.period_secs);
+    let mut tasks = FuturesUnordered::new();
+    let mut period_start = Instant::now();
+    let mut held_by_subnets = 0;
+    // Refreshes are rate-limited separately from probes, per period
+    let mut refreshes: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut checked = 0;
+    let mut checked_in_period = 0;
+    init_progress_bar_with_eta(queue.due_count());
 
-    let mut tasks = Vec::new();
-    for _ in 0..200 {
-        let Some(ip) = candidates.pop() else { break };
-        tasks.push(Box::pin(check_ip(ip.0, states.get(&ip.0).unwrap().up(), data_dir, username)));
+    while !shutdown.requested() {
+        if !once {
+            queue.promote(now_utc());
+        }
+        politeness.poll(now_utc());
+        // Machines of subnets that have too many checks in flight wait for one to end
+        let mut deferred = Vec::new();
+        while tasks.len() < config.scan.concurrency && queue.due_count() > 0 && deferred.len() < config.scan.concurrency {
+            let Some((ip, deadline)) = queue.pop() else { break };
+            if !politeness.subnet_has_room(ip) {
+                politeness.deferred(ip);
+                deferred.push((ip, deadline));
+                continue;
+            }
+            let connections = probes.for_ip(ip).connections();
+            if !politeness.try_start(connections) {
+                queue.requeue(ip, deadline);
+                break;
+            }
+            let now_utc = now_utc();
+            let states = states.read().await;
+            let Some(state) = states.get(&ip) else {
+                politeness.refund(connections);
+                continue;
+            };
+            if state.last_checked() > 0 {
+                METRICS.dispatch_lateness.observe(Duration::from_secs(now_utc.saturating_sub(deadline.time)));
+            }
+            let mut refresh = refresh_reason(ip, state, now_utc, &config.refresh);
+            if let Some(reason) = refresh {
+                if refreshes.values().sum::<usize>() >= config.refresh.max_per_round || config.username.is_none() {
+                    refresh = None;
+                } else {
+                    *refreshes.entry(reason.as_str()).or_default() += 1;
+                }
+            }
+            let candidate = Candidate {
+                ip,
+                was_up: state.up(),
+                has_host_key: state.ssh_host.is_some(),
+                refresh,
+                sample_load: config.load.enabled && state.load_sample_due(now_utc, config.load.interval_secs),
+            };
+            politeness.started(ip);
+            tasks.push(check_ip(candidate, &probes, &ssh_slots, &limiter, config));
+        }
+        let blocked = !deferred.is_empty();
+        if blocked {
+            held_by_subnets += deferred.len();
+            METRICS.held_by_subnets.fetch_add(deferred.len() as u64, Ordering::Relaxed);
+        }
+        for (ip, deadline) in deferred {
+            queue.requeue(ip, deadline);
+        }
+        if once && tasks.is_empty() && queue.due_count() == 0 {
+            break;
+        }
+
+        // Wait for a check to end, the next token or the next machine to become due
+        let can_start = queue.due_count() > 0 && tasks.len() < config.scan.concurrency && !blocked;
+        let wake = match (can_start || politeness.paused(), queue.next_deadline().filter(|_| !once)) {
+            (true, _) => politeness.next_start(),
+            (false, Some(deadline)) => Instant::now() + Duration::from_secs(deadline.saturating_sub(now_utc())),
+            (false, None) => Instant::now() + period,
+        };
+        tokio::select! {
+            Some(result) = tasks.next(), if !tasks.is_empty() => {
+                let ip = result.ip;
+                politeness.finished(ip);
+                let deadline = record_result(result, states, storage, journal, config).await;
+                queue.push(ip, deadline);
+                checked += 1;
+                checked_in_period += 1;
+                set_progress_bar_max(checked_in_period + queue.due_count() + tasks.len());
+                inc_progress_bar();
+                // Failures are logged and retried on the next save, the scan goes on
+                if checked % config.scan.save_every == 0 {
+                    let mut saved = true;
+                    if let Err(e) = journal.sync().await {
+                        print_progress_bar_info("Failed", &format!("to sync journal: {e}"), Color::Red, Style::Bold);
+                        saved = false;
+                    }
+                    // Stats are rendered under the lock but written without it, so that retries don't hold back checks
+                    let (stats, tracked) = {
+                        let states = states.read().await;
+                        if let Err(e) = storage.flush(&states).await {
+                            print_progress_bar_info("Failed", &format!("to save states: {e}"), Color::Red, Style::Bold);
+                            saved = false;
+                        }
+                        let tracked = identity::track(&states);
+                        (render_stats(&states, &tracked, config, politeness.throttling()), tracked)
+                    };
+                    let site = render_site(&tracked, data_dir).await;
+                    *fleet.write().await = tracked;
+                    if let Err(e) = write_stats(data_dir, &stats).await {
+                        print_progress_bar_info("Failed", &format!("to update stats: {e}"), Color::Red, Style::Bold);
+                        saved = false;
+                    }
+                    let site = match site {
+                        Ok(site) => write_site(&site).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = site {
+                        print_progress_bar_info("Failed", &format!("to update site: {e}"), Color::Red, Style::Bold);
+                        saved = false;
+                    }
+                    if saved {
+                        print_progress_bar_info("Updated", "Stats have been updated", Color::Green, Style::Bold)
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(wake.into()) => (),
+            _ = shutdown.wait() => (),
+        }
+
+        if period_start.elapsed() >= period {
+            finalize_progress_bar();
+            if !refreshes.is_empty() {
+                let reasons = refreshes.iter().map(|(reason, count)| format!("{count} {reason}")).collect::<Vec<_>>().join(", ");
+                println!("Refreshed extended info of machines that stay up: {reasons}");
+            }
+            let mut limits = format!("at most {} per second", politeness.rate_limit());
+            if politeness.quiet() {
+                limits.push_str(" (quiet hours)");
+            }
+            if politeness.paused() {
+                limits.push_str(", paused");
+            }
+            if held_by_subnets > 0 {
+                limits.push_str(&format!(", {held_by_subnets} starts held back by subnet caps"));
+            }
+            let elapsed = period_start.elapsed();
+            METRICS.period_checks.store(checked_in_period as u64, Ordering::Relaxed);
+            println!("Checked {checked_in_period} machines in {}s ({:.1} per second), {limits}", elapsed.as_secs(), checked_in_period as f64 / elapsed.as_secs_f64());
+            report_schedule(&*states.read().await, config);
+            refreshes.clear();
+            held_by_subnets = 0;
+            period_start = Instant::now();
+            checked_in_period = 0;
+            init_progress_bar_with_eta(queue.due_count() + tasks.len());
+        }
     }
 
-    let mut i = 0;
+    // Results of checks that end before the deadline are kept, the others are lost
+    let deadline = Instant::now() + Duration::from_secs(config.scan.shutdown_timeout_secs);
     while !tasks.is_empty() {
-        let ((ip, up, extended_info), _, new_tasks) = select_all(tasks).await;
-        tasks = new_tasks;
-        if let Some(ip) = candidates.pop() {
-            tasks.push(Box::pin(check_ip(ip.0, states.get(&ip.0).unwrap().up(), data_dir, username)));
-        }
-        let now_utc = now_utc();
-        let state = states.entry(ip).or_default();
-        match extended_info {
-            Some(Ok(extended_info)) => state.extended_info = Some(extended_info),
-            Some(Err(err)) => {
-                print_progress_bar_info("Failed", &format!("to load extended info for {ip}: {err:?}"), Color::Yellow, Style::Bold);
-                state.extended_info = None;
+        match tokio::time::timeout_at(deadline.into(), tasks.next()).await {
+            Ok(Some(result)) => {
+                politeness.finished(result.ip);
+                record_result(result, states, storage, journal, config).await;
+                inc_progress_bar();
+            },
+            Ok(None) => break,
+            Err(_) => {
+                print_progress_bar_info("Abandoned", &format!("{} checks still in flight", tasks.len()), Color::Yellow, Style::Bold);
+                break;
             },
-            None => (),
-        }
-        state.checked(up, now_utc);
-        if (i % 500) == 0 {
-            update_stats(states, data_dir).await;
-            save_states(states, data_dir).await;
-            update_site(states, data_dir).await;
-            print_progress_bar_info("Updated", "Stats have been updated", Color::Green, Style::Bold)
         }
-        i += 1;
-        inc_progress_bar();
     }
     finalize_progress_bar();
+    report_schedule(&*states.read().await, config);
+    politeness.throttling().clone()
 }
 
-async fn update_stats(states: &States, data_dir: &str) {
+/// Writes the journal, states, stats and site one last time, returning whether the journal and states were saved.
+/// Stats and site can be generated again from the states, so failing to write them is only logged.
+async fn final_save(states: &States, storage: &mut dyn Storage, journal: &mut Journal, config: &Config, throttling: &Throttling) -> bool {
+    let mut saved = true;
+    if let Err(e) = journal.sync().await {
+        eprintln!("Failed to sync journal: {e}");
+        saved = false;
+    }
+    if let Err(e) = storage.flush(states).await {
+        eprintln!("Failed to save states: {e}");
+        saved = false;
+    }
+    let fleet = identity::track(states);
+    if let Err(e) = update_stats(states, &fleet, config, throttling).await {
+        eprintln!("Failed to update stats: {e}");
+    }
+    if let Err(e) = update_site(&fleet, &config.data_dir).await {
+        eprintln!("Failed to update site: {e}");
+    }
+    saved
+}
+
+fn report_schedule(states: &States, config: &Config) {
+    let report = schedule::report(states, now_utc(), &config.schedule);
+    METRICS.set_schedule(&report);
+    for line in schedule::format_report(&report) {
+        println!("Behind schedule, {line}");
+    }
+}
+
+async fn update_stats(states: &States, fleet: &Fleet, config: &Config, throttling: &Throttling) -> Result<(), Error> {
+    write_stats(&config.data_dir, &render_stats(states, fleet, config, throttling)).await
+}
+
+/// `fleet` is tracked from `states`, `throttling` is what politeness did while scanning, empty for other commands
+fn render_stats(states: &States, fleet: &Fleet, config: &Config, throttling: &Throttling) -> String {
     let now_utc = now_utc();
+    let predictor = Predictor::learn(&fleet.machines, now_utc);
     let mut lines = Vec::new();
     for (ip, state) in states {
         let last_change_utc = state.last_change();
@@ -144,22 +441,60 @@ async fn update_stats(states: &States, data_dir: &str) {
             continue;
         }
         let hostname = state.extended_info.as_ref().map(|info| info.hostname.as_str()).unwrap_or("");
-        let cpu = state.extended_info.as_ref().and_then(|info| info.cpu()).unwrap_or("");
-        let mem = state.extended_info.as_ref().and_then(|info| info.ram()).unwrap_or(0);
-        let swap = state.extended_info.as_ref().and_then(|info| info.swap()).unwrap_or(0);
-        let mac = state.extended_info.as_ref().and_then(|info| info.mac()).unwrap_or("");
-        lines.push(format!("{ip},{up},{uptime},{downtime},{last_change_utc},{last_checked_utc},{hostname},{cpu},{mem},{swap},{mac}"));
+        let hardware = state.extended_info.as_ref().map(|info| &info.hardware);
+        let csv_field = |value: Option<&String>| value.map(|value| value.replace(',', " ")).unwrap_or_default();
+        let cpu = csv_field(hardware.and_then(|hw| hw.cpu_model.as_ref()));
+        let mem = hardware.and_then(|hw| hw.ram).unwrap_or(0) / 1000;
+        let swap = hardware.and_then(|hw| hw.swap).unwrap_or(0) / 1000;
+        let mac = hardware.and_then(|hw| hw.mac()).unwrap_or("");
+        let cores = hardware.and_then(|hw| hw.cores).map(|cores| cores.to_string()).unwrap_or_default();
+        let threads = hardware.and_then(|hw| hw.threads).map(|threads| threads.to_string()).unwrap_or_default();
+        let cpu_mhz = hardware.and_then(|hw| hw.cpu_mhz).map(|mhz| mhz.to_string()).unwrap_or_default();
+        let kernel = csv_field(hardware.and_then(|hw| hw.kernel.as_ref()));
+        let os = csv_field(hardware.and_then(|hw| hw.os.as_ref()));
+        let disk_gb = hardware.map(|hw| hw.disk_size() / 1_000_000_000).filter(|size| *size > 0).map(|size| size.to_string()).unwrap_or_default();
+        let gpu = hardware.map(|hw| hw.gpus.join(" / ").replace(',', " ")).unwrap_or_default();
+        let up_probability_next_hour = predictor.up_probability(*ip, now_utc + 3600).map(|p| format!("{p:.3}")).unwrap_or_default();
+        let expected_shutdown_utc = predictor.expected_shutdown(*ip, state, now_utc).map(|t| t.to_string()).unwrap_or_default();
+        let probe = state.last_probe.as_ref().map(|probe| probe.kind.as_str()).unwrap_or("");
+        let probe_status = state.last_probe.as_ref().and_then(|probe| probe.status).map(|status| status.as_str()).unwrap_or("");
+        let ssh_banner = state.ssh_host.as_ref().map(|ssh| ssh.banner.replace(',', " ")).unwrap_or_default();
+        let host_key = state.ssh_host.as_ref().and_then(|ssh| ssh.current_key());
+        let host_key_type = host_key.map(|key| key.key_type.as_str()).unwrap_or("");
+        let host_key_fingerprint = host_key.map(|key| key.fingerprint.as_str()).unwrap_or("");
+        let host_key_changed_utc = state.ssh_host.as_ref().and_then(|ssh| ssh.key_changed_at()).map(|t| t.to_string()).unwrap_or_default();
+        let info_collected_utc = state.extended_info.as_ref().map(|info| info.collected_at).filter(|t| *t > 0).map(|t| t.to_string()).unwrap_or_default();
+        let load = state.current_load();
+        let (load_1m, load_5m, load_15m) = match load {
+            Some(sample) => (format!("{:.2}", sample.load[0]), format!("{:.2}", sample.load[1]), format!("{:.2}", sample.load[2])),
+            None => Default::default(),
+        };
+        let cpu_usage = load.and_then(|sample| sample.cpu_usage).map(|usage| format!("{usage:.3}")).unwrap_or_default();
+        let mem_available = load.and_then(|sample| sample.mem_available).map(|mem| (mem / 1000).to_string()).unwrap_or_default();
+        let users = load.and_then(|sample| sample.users).map(|users| users.to_string()).unwrap_or_default();
+        let load_sampled_utc = load.map(|sample| sample.time.to_string()).unwrap_or_default();
+        let identity = match fleet.leases.get(ip).and_then(|leases| leases.last()).map(|lease| &lease.identity) {
+            Some(Identity::Ip(_)) | None => String::new(),
+            Some(identity) => identity.to_string().replace(',', " "),
+        };
+        let room = room_of(*ip, hostname).replace(',', " ");
+        let deadline = schedule::deadline(*ip, state, now_utc, &config.schedule);
+        let (priority, next_check_utc) = (deadline.priority.as_str(), deadline.time);
+        let (throttled, paused) = (throttling.quiet, throttling.paused);
+        let deferred = throttling.deferred.get(ip).copied().unwrap_or(0);
+        lines.push(format!("{ip},{up},{uptime},{downtime},{last_change_utc},{last_checked_utc},{hostname},{cpu},{mem},{swap},{mac},{up_probability_next_hour},{expected_shutdown_utc},{probe},{probe_status},{ssh_banner},{host_key_type},{host_key_fingerprint},{host_key_changed_utc},{cores},{threads},{cpu_mhz},{kernel},{os},{disk_gb},{gpu},{info_collected_utc},{load_1m},{load_5m},{load_15m},{cpu_usage},{mem_available},{users},{load_sampled_utc},{identity},{room},{priority},{next_check_utc},{throttled},{deferred},{paused}"));
     }
     lines.sort();
-    let mut file = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(format!("{data_dir}/stats.csv"))
-        .await
-        .expect("Failed to open stats.csv");
-    file.write_all(b"ip,up,uptime,downtime,last_change_utc,last_checked_utc,hostname,cpu,mem_kB,swap_kB,mac\n").await.expect("Failed to write to stats.csv");
-    file.write_all(lines.join("\n").as_bytes()).await.expect("Failed to write to stats.csv");
+    let mut content = String::from("ip,up,uptime,downtime,last_change_utc,last_checked_utc,hostname,cpu,mem_kB,swap_kB,mac,up_probability_next_hour,expected_shutdown_utc,probe,probe_status,ssh_banner,host_key_type,host_key_fingerprint,host_key_changed_utc,cores,threads,cpu_mhz,kernel,os,disk_GB,gpu,info_collected_utc,load_1m,load_5m,load_15m,cpu_usage,mem_available_kB,users,load_sampled_utc,identity,room,priority,next_check_utc,throttled,deferred,paused\n");
+    content.push_str(&lines.join("\n"));
+    content
+}
+
+async fn write_stats(data_dir: &str, content: &str) -> Result<(), Error> {
+    let path = format!("{data_dir}/stats.csv");
+    with_retries("write stats.csv", || async {
+        tokio::fs::write(&path, content).await.map_err(|e| Error::io(&path, e))
+    }).await
 }
 
 fn format_duration(seconds: u64) -> String {
@@ -176,33 +511,25 @@ fn format_duration(seconds: u64) -> String {
     }
 }
 
-async fn update_site(states: &States, data_dir: &str) {
-    let rooms = [
-        ("lin-2d", "Machines virtuelles"),
-        ("stpi-aio-", "STPI (AOI)"),
-        ("stpi-lbs-", "STPI (LBS)"),
-        ("stpi-dell390-", "STPI (Dell)"),
-        ("stpi-", "STPI"),
-        ("boar203-", "Bo-A-R2-03"),
-        ("boar205-", "Bo-A-R2-05"),
-        ("boar207-", "Bo-A-R2-07"),
-        ("mahr203-", "Ma-H-R2-03"),
-        ("mahr205-", "Ma-H-R2-05"),
-        ("mahr207-", "Ma-H-R2-07"),
-        ("mahr209-", "Ma-H-R2-09"),
-        ("iti-mahr211-", "Ma-H-R2-11"),
-        ("iti-mahr213-", "Ma-H-R2-13"),
-        ("iti-mahr215-", "Ma-H-R2-15"),
-        ("perf-", "PERF"),
-        ("ep-", "EP"),
-        ("", "Inconnu")
-    ];
+/// Text between two markers of the site template
+fn template_part(text: &str, begin: &'static str, end: &'static str) -> Result<String, Error> {
+    get_all_between_strict(text, begin, end).map(String::from).ok_or(Error::Template(begin))
+}
+
+async fn update_site(fleet: &Fleet, data_dir: &str) -> Result<(), Error> {
+    write_site(&render_site(fleet, data_dir).await?).await
+}
 
+/// Fills the site template, inlining the script and style of `data_dir`
+async fn render_site(fleet: &Fleet, data_dir: &str) -> Result<String, Error> {
     let now_utc = now_utc();
+    let predictor = Predictor::learn(&fleet.machines, now_utc);
     let mut total_up_count = 0;
     let mut total_machine_count = 0;
     let mut per_room = HashMap::new();
-    for (ip, state) in states {
+    // Machines that got another IP are counted once, with their whole history
+    for machine in &fleet.machines {
+        let (ip, state) = (machine.ip(), &machine.state);
         let (up, uptime, _) = state.times_since(now_utc - 30*86400, now_utc);
         if up {
             total_up_count += 1;
@@ -210,17 +537,13 @@ async fn update_site(states: &States, data_dir: &str) {
         if uptime > 0 {
             total_machine_count += 1;
             let hostname = state.extended_info.as_ref().map(|info| info.hostname.as_str()).unwrap_or("");
-            let mut room = "Inconnu";
-            for (prefix, r) in rooms {
-                if hostname.starts_with(prefix) {
-                    room = r;
-                    break;
-                }
-            }
+            let room = room_of(ip, hostname);
             per_room.entry(room).or_insert_with(Vec::new).push((ip, state));
         }
     }
-    let max_machines_per_room = per_room.values().map(|machines| machines.len()).max().unwrap_or(0);
+    let max_machines_per_room = per_room.iter()
+        .map(|(room, machines)| rooms::catalog().get(room).and_then(|info| info.capacity).map(|capacity| capacity as usize).unwrap_or(machines.len()))
+        .max().unwrap_or(0);
 
     let mut pattern = include_str!("../site/pattern.html").to_string();
 
@@ -233,15 +556,17 @@ async fn update_site(states: &States, data_dir: &str) {
     pattern = pattern.replace("[SUMMARY-MESSAGE]", &summary);
 
     // Inline external JS and CSS
-    let script = std::fs::read_to_string(format!("{}/site/script.js", data_dir)).expect("Failed to read script.js");
+    let script_path = format!("{data_dir}/site/script.js");
+    let script = tokio::fs::read_to_string(&script_path).await.map_err(|e| Error::io(&script_path, e))?;
     pattern = pattern.replace("<script defer type=\"module\" src=\"script.js\"></script>", &format!("<script type=\"module\">{}</script>", script));
-    let style = std::fs::read_to_string(format!("{}/site/style.css", data_dir)).expect("Failed to read style.css");
+    let style_path = format!("{data_dir}/site/style.css");
+    let style = tokio::fs::read_to_string(&style_path).await.map_err(|e| Error::io(&style_path, e))?;
     pattern = pattern.replace("<link rel=\"stylesheet\" href=\"style.css\">", &format!("<style>{}</style>", style));
 
     // Chart
-    let last_generated_pattern = get_all_between_strict(&pattern, "/*BEGIN-LAST-GENERATED*/", "/*END-LAST-GENERATED*/").unwrap().to_string();
-    let datapoints_pattern = get_all_between_strict(&pattern, "/*BEGIN-DATAPOINTS*/", "/*END-DATAPOINTS*/").unwrap().to_string();
-    let max_up_count_pattern = get_all_between_strict(&pattern, "/*BEGIN-MAX-UP-COUNT*/", "/*END-MAX-UP-COUNT*/").unwrap().to_string();
+    let last_generated_pattern = template_part(&pattern, "/*BEGIN-LAST-GENERATED*/", "/*END-LAST-GENERATED*/")?;
+    let datapoints_pattern = template_part(&pattern, "/*BEGIN-DATAPOINTS*/", "/*END-DATAPOINTS*/")?;
+    let max_up_count_pattern = template_part(&pattern, "/*BEGIN-MAX-UP-COUNT*/", "/*END-MAX-UP-COUNT*/")?;
     let mut datapoints = Vec::new();
     let mut max_up_count = 0;
     for i in (0..7*24).rev() {
@@ -259,12 +584,16 @@ async fn update_site(states: &States, data_dir: &str) {
     pattern = pattern.replace(&max_up_count_pattern, &max_up_count.to_string());
 
     // Rooms
-    let room_pattern = get_all_between_strict(&pattern, "<!--BEGIN-ROOM-->", "<!--END-ROOM-->").unwrap().to_string();
-    let machine_pattern = get_all_between_strict(&room_pattern, "<!--BEGIN-MACHINE-->", "<!--END-MACHINE-->").unwrap().to_string();
-    let row_pattern = get_all_between_strict(&room_pattern, "<!--BEGIN-ROW-->", "<!--END-ROW-->").unwrap().to_string();
+    let room_pattern = template_part(&pattern, "<!--BEGIN-ROOM-->", "<!--END-ROOM-->")?;
+    let machine_pattern = template_part(&room_pattern, "<!--BEGIN-MACHINE-->", "<!--END-MACHINE-->")?;
+    let row_pattern = template_part(&room_pattern, "<!--BEGIN-ROW-->", "<!--END-ROW-->")?;
     let mut rooms_final = String::new();
+    let catalog = rooms::catalog();
+    let mut per_room: Vec<_> = per_room.into_iter().collect();
+    per_room.sort_by_key(|(room, _)| catalog.names().position(|name| name == *room));
     for (room, machines) in per_room {
         let mut room_final = room_pattern.clone();
+        let room_info = catalog.get(room);
 
         // Rows
         let mut up_count = 0;
@@ -300,18 +629,29 @@ async fn update_site(states: &States, data_dir: &str) {
                 lowest_down_duration = duration;
             }
 
+            // Expected shutdown
+            let (shutdown, shutdown_value) = match (up, predictor.expected_shutdown(*ip, state, now_utc)) {
+                (false, _) => (String::from("-"), 0),
+                (true, Some(shutdown)) => (format!("dans {}", format_duration(shutdown - now_utc)), shutdown - now_utc),
+                (true, None) if predictor.up_probability(*ip, now_utc).is_some() => (String::from("> 7 jours"), 7*86400),
+                (true, None) => (String::from("inconnue"), 0),
+            };
+            row_final = row_final.replace("[ROW-SHUTDOWN-VALUE]", &shutdown_value.to_string());
+            row_final = row_final.replace("[ROW-SHUTDOWN]", &shutdown);
+
             // Reliability
             let reliability = format!("{:.2}%", uptime as f64 / (uptime + downtime) as f64 * 100.0);
             row_final = row_final.replace("[ROW-RELIABILITY]", &reliability);
 
             // System info
-            let cpu = state.extended_info.as_ref().and_then(|info| info.cpu()).unwrap_or("unknown");
-            let ram_value = state.extended_info.as_ref().and_then(|info| info.ram()).unwrap_or(0);
+            let hardware = state.extended_info.as_ref().map(|info| &info.hardware);
+            let cpu = hardware.and_then(|hw| hw.cpu_model.as_deref()).unwrap_or("unknown");
+            let ram_value = hardware.and_then(|hw| hw.ram).unwrap_or(0);
             let ram = match ram_value {
                 0 => String::from("unknown"),
                 _ => format!("{:.1} Go", ram_value as f64 / 1_000_000_000.0),
             };
-            let ram_swap_value = ram_value + state.extended_info.as_ref().and_then(|info| info.swap()).unwrap_or(0);
+            let ram_swap_value = ram_value + hardware.and_then(|hw| hw.swap).unwrap_or(0);
             let mem_swap = match ram_swap_value {
                 0 => String::from("unknown"),
                 _ => format!("{:.1} Go", ram_swap_value as f64 / 1_000_000_000.0),
@@ -322,12 +662,39 @@ async fn update_site(states: &States, data_dir: &str) {
             row_final = row_final.replace("[ROW-RAM-SWAP]", &mem_swap);
             row_final = row_final.replace("[ROW-RAM-SWAP-VALUE]", &ram_swap_value.to_string());
 
+            // Load, least loaded first when sorted, machines without a sample last
+            let load = match state.current_load() {
+                Some(sample) => {
+                    let cpu = sample.cpu_usage.map(|usage| format!(" ({:.0}% CPU)", usage * 100.0)).unwrap_or_default();
+                    format!("{:.2}{cpu}", sample.load[0])
+                },
+                None => String::from("-"),
+            };
+            let load_value = format!("{:.3}", state.load_score().min(1000.0));
+            let users = state.current_load().and_then(|sample| sample.users);
+            row_final = row_final.replace("[ROW-LOAD-VALUE]", &load_value);
+            row_final = row_final.replace("[ROW-LOAD]", &load);
+            row_final = row_final.replace("[ROW-USERS-VALUE]", &users.map(|users| users.to_string()).unwrap_or_else(|| String::from("1000")));
+            row_final = row_final.replace("[ROW-USERS]", &users.map(|users| users.to_string()).unwrap_or_else(|| String::from("-")));
+
             rows_final.push_str(&row_final);
         }
         room_final = room_final.replace(row_pattern.as_str(), &rows_final);
 
         // Room info
-        room_final = room_final.replace("[ROOM-NAME]", room);
+        let mut room_name = room.to_string();
+        if let Some(info) = room_info {
+            match (&info.building, info.floor) {
+                (Some(building), Some(floor)) => room_name.push_str(&format!(" ({building}, étage {floor})")),
+                (Some(building), None) => room_name.push_str(&format!(" ({building})")),
+                (None, Some(floor)) => room_name.push_str(&format!(" (étage {floor})")),
+                (None, None) => (),
+            }
+            if !info.is_open(now_utc) {
+                room_name.push_str(" - fermée");
+            }
+        }
+        room_final = room_final.replace("[ROOM-NAME]", &room_name);
         room_final = room_final.replace("[ROOM-UP-COUNT]", &up_count.to_string());
         room_final = room_final.replace("[ROOM-MACHINE-COUNT]", &machines.len().to_string());
         let duration_fmt = match up_count == 0 {
@@ -348,7 +715,9 @@ async fn update_site(states: &States, data_dir: &str) {
             machine_final = machine_final.replace("[MACHINE-STATE]", state);
             machines_final.push(machine_final);
         }
-        while machines_final.len() < max_machines_per_room {
+        // Machines that were never seen are shown as missing, up to the capacity of the room when it is known
+        let slots = room_info.and_then(|info| info.capacity).map(|capacity| capacity as usize).unwrap_or(max_machines_per_room);
+        while machines_final.len() < slots {
             let mut machine_final = machine_pattern.clone();
             machine_final = machine_final.replace("[MACHINE-STATE]", "missing");
             machines_final.push(machine_final);
@@ -358,34 +727,27 @@ async fn update_site(states: &States, data_dir: &str) {
         rooms_final.push_str(&room_final);
     }
     pattern = pattern.replace(&room_pattern, &rooms_final);
+    Ok(pattern)
+}
 
-    std::fs::write("site/index.html", pattern).expect("Failed to write site/index.html");
+async fn write_site(content: &str) -> Result<(), Error> {
+    with_retries("write site/index.html", || async {
+        tokio::fs::write("site/index.html", content).await.map_err(|e| Error::io("site/index.html", e))
+    }).await
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ExtendedInfo {
     hostname: String,
-    cpuinfo: String,
-    meminfo: String,
-    ipaddr: String,
-}
-
-impl ExtendedInfo {
-    pub fn cpu(&self) -> Option<&str> {
-        get_all_between_strict(&self.cpuinfo, "model name	: ", "\n")
-    }
-
-    pub fn ram(&self) -> Option<u64> {
-        get_all_between_strict(&self.meminfo, "MemTotal:", " kB").and_then(|s| s.trim().parse().ok()).map(|s: u64| s * 1000)
-    }
-
-    pub fn swap(&self) -> Option<u64> {
-        get_all_between_strict(&self.meminfo, "SwapTotal:", " kB").and_then(|s| s.trim().parse().ok()).map(|s: u64| s * 1000)
-    }
-
-    pub fn mac(&self) -> Option<&str> {
-        get_all_between_strict(&self.ipaddr, "    link/ether ", " brd").map(|s| s.trim())
-    }
+    hardware: HardwareInfo,
+    /// Command outputs the hardware was parsed from, only kept when `ssh.keep_raw` is set
+    raw: Option<Box<RawInfo>>,
+    /// Unknown (0) for info collected by older versions
+    #[serde(default)]
+    collected_at: u64,
+    /// Time the same info was first collected, it was collected again at `collected_at`
+    #[serde(default)]
+    first_collected_at: u64,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -395,6 +757,15 @@ struct MachineState {
     changes: Vec<u64>,
     last_checked: u64,
     pub extended_info: Option<ExtendedInfo>,
+    /// Result of the latest probe, telling apart machines that are off from those that only have the service down
+    pub last_probe: Option<ProbeDetails>,
+    pub ssh_host: Option<SshHost>,
+    /// Time and message of the last failed extended info collection, cleared on success
+    pub last_info_error: Option<(u64, String)>,
+    /// Oldest first
+    pub load: Vec<LoadSample>,
+    /// Previous extended info, oldest first, one snapshot per change
+    pub info_history: Vec<ExtendedInfo>,
 }
 
 impl MachineState {
@@ -457,58 +828,389 @@ impl MachineState {
     }
 }
 
-async fn load_extented_info(ip: Ipv4Addr, data_dir: &str, username : &str) -> Result<ExtendedInfo, String> {
-    let r = timeout(
-        Duration::from_secs(3),
-        run_shell_command(format!("ssh -i {data_dir}/ssh-key -oBatchMode=yes -oStrictHostKeyChecking=no \"{username}@{ip}\" \"hostname; echo MUBELOTIX-SEPARATOR; cat /proc/cpuinfo; echo MUBELOTIX-SEPARATOR; cat /proc/meminfo; echo MUBELOTIX-SEPARATOR; ip addr\""))
-    ).await;
-    let r = match r {
-        Ok(r) => r?,
-        Err(_) => return Err(String::from("Timeout")),
+/// Runs on an authenticated connection.
+/// Each command has its own timeout, so a machine that answers slowly takes up to 8 × (timeout_secs + 1) seconds.
+async fn load_extented_info(client: &SshClient, config: &Config) -> Result<ExtendedInfo, SshError> {
+    let hostname = client.exec("hostname").await?;
+    if hostname.trim().is_empty() {
+        return Err(SshError::InvalidResponse(String::from("Empty hostname")));
+    }
+    // Only the first commands are available everywhere
+    let raw = RawInfo {
+        cpuinfo: client.exec("cat /proc/cpuinfo").await?,
+        meminfo: client.exec("cat /proc/meminfo").await?,
+        ip_addr: client.exec("ip addr").await?,
+        kernel: client.exec("uname -r").await.ok(),
+        os_release: client.exec("cat /etc/os-release").await.ok(),
+        max_freq: client.exec("cat /sys/devices/system/cpu/cpu0/cpufreq/cpuinfo_max_freq").await.ok(),
+        lsblk: client.exec("lsblk -bdno NAME,SIZE,TYPE").await.ok(),
+        lspci: client.exec("lspci").await.ok(),
     };
-    
-    let inner = |data: &str| -> Option<ExtendedInfo> {
-        let hostname = get_all_before_strict(data, "MUBELOTIX-SEPARATOR")?;
-        let data = get_all_after_strict(data, "MUBELOTIX-SEPARATOR")?;
-        let cpuinfo = get_all_before_strict(data, "MUBELOTIX-SEPARATOR")?;
-        let data = get_all_after_strict(data, "MUBELOTIX-SEPARATOR")?;
-        let meminfo = get_all_before_strict(data, "MUBELOTIX-SEPARATOR")?;
-        let data = get_all_after_strict(data, "MUBELOTIX-SEPARATOR")?;
-        let ipaddr = data;
-        Some(ExtendedInfo {
-            hostname: hostname.trim().replace(',', " "),
-            cpuinfo: cpuinfo.trim().replace(',', " "),
-            meminfo: meminfo.trim().replace(',', " "),
-            ipaddr: ipaddr.trim().replace(',', " "),
-        })
+
+    Ok(ExtendedInfo {
+        hostname: hostname.trim().replace(',', " "),
+        hardware: HardwareInfo::parse(&raw),
+        raw: config.ssh.keep_raw.then(|| Box::new(raw)),
+        // Dated by record_result, like the host key seen during the same check
+        collected_at: 0,
+        first_collected_at: 0,
+    })
+}
+
+/// Runs on an authenticated connection
+async fn load_sample(client: &SshClient, time_to_wait: Duration) -> Result<LoadSample, SshError> {
+    let time = now_utc();
+    let raw = RawLoad {
+        loadavg: client.exec("cat /proc/loadavg").await?,
+        meminfo: client.exec("cat /proc/meminfo").await.ok(),
+        // One more second is spent measuring CPU usage
+        stat: client.exec_within("head -n 1 /proc/stat; sleep 1; head -n 1 /proc/stat", time_to_wait + Duration::from_secs(1)).await.ok(),
+        who: client.exec("who").await.ok(),
     };
+    LoadSample::parse(&raw, time).ok_or_else(|| SshError::InvalidResponse(format!("Invalid load average {:?}", raw.loadavg.trim())))
+}
+
+/// Restores states from storage and makes them match the configured targets
+async fn load_states(storage: &mut dyn Storage, config: &Config) -> States {
+    let ips = match config.targets.generate_ips() {
+        Ok(ips) => ips,
+        Err(e) => {
+            eprintln!("Invalid targets: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let journal_path = config.journal_path();
+    let journal_exists = config.journal.enabled && journal_path.exists();
+    let loaded = match storage.load().await {
+        // Nothing was saved, but the journal has the history
+        Ok(states) if states.is_empty() && journal_exists => {
+            eprintln!("No saved states. Replaying journal.");
+            None
+        }
+        Ok(states) => Some(states),
+        // Only states that can't be decoded are rebuilt, the next save would replace a newer or unreadable states.bin with older data
+        Err(e @ Error::Format(_)) if journal_exists => {
+            eprintln!("{e}. Replaying journal.");
+            None
+        }
+        Err(e) => {
+            eprintln!("Failed to load states: {e}");
+            std::process::exit(1);
+        }
+    };
+    let mut states = match loaded {
+        Some(states) => states,
+        None => match journal::replay(&journal_path).await {
+            Ok(states) => states,
+            Err(e) => {
+                eprintln!("Failed to replay journal: {e}");
+                std::process::exit(1);
+            }
+        },
+    };
+    prune_to_targets(&mut states, ips);
+    states
+}
+
+/// Forgets machines that are out of scope and adds the targets that were never seen
+fn prune_to_targets(states: &mut States, ips: HashSet<Ipv4Addr>) {
+    states.retain(|ip, _| ips.contains(ip));
+    for ip in ips {
+        states.entry(ip).or_default();
+    }
+}
+
+fn format_timestamp(time_utc: u64) -> String {
+    match chrono::DateTime::from_timestamp(time_utc as i64, 0) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => time_utc.to_string(),
+    }
+}
+
+fn inspect(ip: Ipv4Addr, state: &MachineState, history: &[(u64, bool)], leases: &[Lease]) {
+    let now_utc = now_utc();
+    let hostname = state.extended_info.as_ref().map(|info| info.hostname.as_str()).unwrap_or("unknown hostname");
+    println!("{ip} ({hostname})");
+    if !state.has_ever_been_up() {
+        println!("Never seen up");
+        return;
+    }
+
+    let (up, uptime, downtime) = state.times_since(now_utc - 30*86400, now_utc);
+    let up_fmt = match up {
+        true => "up",
+        false => "down",
+    };
+    println!("Status: {up_fmt} for {}", format_duration(now_utc - state.last_change()));
+    println!("Last checked: {}", format_timestamp(state.last_checked()));
+    if let Some(probe) = &state.last_probe {
+        let port = probe.port.map(|port| format!(" on port {port}")).unwrap_or_default();
+        println!("Last probe: {}{port} in {} ms ({})", probe.kind, probe.elapsed_ms, probe.outcome);
+    }
+    if let Some(ssh) = &state.ssh_host {
+        println!("SSH server: {}", ssh.banner);
+        for key in ssh.keys.iter().rev() {
+            println!("Host key: {} {} (seen from {} to {})", key.key_type, key.fingerprint, format_timestamp(key.first_seen), format_timestamp(key.last_seen));
+        }
+    }
+    if uptime + downtime > 0 {
+        println!("Reliability (30 days): {:.2}%", uptime as f64 / (uptime + downtime) as f64 * 100.0);
+    }
+    if let Some(info) = &state.extended_info {
+        let hw = &info.hardware;
+        let unknown = || String::from("unknown");
+        println!("CPU: {}", hw.cpu_model.as_deref().unwrap_or("unknown"));
+        println!("Cores/threads: {}/{}", hw.cores.map(|c| c.to_string()).unwrap_or_else(unknown), hw.threads.map(|t| t.to_string()).unwrap_or_else(unknown));
+        println!("Frequency: {}", hw.cpu_mhz.map(|mhz| format!("{mhz} MHz")).unwrap_or_else(unknown));
+        println!("RAM: {} kB", hw.ram.map(|ram| (ram / 1000).to_string()).unwrap_or_else(unknown));
+        println!("Swap: {} kB", hw.swap.map(|swap| (swap / 1000).to_string()).unwrap_or_else(unknown));
+        println!("OS: {} (kernel {})", hw.os.as_deref().unwrap_or("unknown"), hw.kernel.as_deref().unwrap_or("unknown"));
+        for disk in &hw.disks {
+            println!("Disk: {} ({:.0} GB)", disk.name, disk.size as f64 / 1_000_000_000.0);
+        }
+        for gpu in &hw.gpus {
+            println!("GPU: {gpu}");
+        }
+        for nic in &hw.nics {
+            println!("Interface {}: {} {}", nic.name, nic.mac.as_deref().unwrap_or("-"), nic.ips.join(" "));
+        }
+        if info.collected_at > 0 {
+            println!("Collected: {}", format_timestamp(info.collected_at));
+        }
+    }
+    for (previous, next) in state.snapshots().zip(state.snapshots().skip(1)) {
+        let changes = inventory::diff(previous, next).iter().map(|change| change.to_string()).collect::<Vec<_>>().join(", ");
+        let time = match next.first_collected_at {
+            0 => String::from("unknown time"),
+            time => format_timestamp(time),
+        };
+        println!("Changed at {time}: {changes}");
+    }
+    if let Some((time, error)) = &state.last_info_error {
+        println!("Last collection failed at {}: {error}", format_timestamp(*time));
+    }
+    if let Some(sample) = state.current_load() {
+        let cpu = sample.cpu_usage.map(|usage| format!(", {:.0}% CPU", usage * 100.0)).unwrap_or_default();
+        let mem = sample.mem_available.map(|mem| format!(", {} kB available", mem / 1000)).unwrap_or_default();
+        let users = sample.users.map(|users| format!(", {users} users")).unwrap_or_default();
+        println!("Load: {:.2} {:.2} {:.2}{cpu}{mem}{users} (at {})", sample.load[0], sample.load[1], sample.load[2], format_timestamp(sample.time));
+    }
+
+    if leases.len() > 1 {
+        println!("Leases:");
+        for lease in leases {
+            let to = lease.to.map(format_timestamp).unwrap_or_else(|| String::from("now"));
+            let from = match lease.from {
+                0 => String::from("first seen"),
+                from => format_timestamp(from),
+            };
+            println!("  {from} - {to}  {}", lease.identity);
+        }
+    }
+
+    println!("Timeline:");
+    for (time, up) in history {
+        let up_fmt = match up {
+            true => "up",
+            false => "down",
+        };
+        println!("  {}  {up_fmt}", format_timestamp(*time));
+    }
+}
 
-    inner(&r).ok_or_else(|| String::from("Invalid response"))
+fn listen_for_shutdown() -> Shutdown {
+    match Shutdown::listen() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| String::from("."));
-    let username = std::env::var("INSA_USERNAME").ok();
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+    let data_dir = config.data_dir.as_str();
+    let command = args.command.unwrap_or_default();
 
-    if username.is_none() {
-        eprintln!("INSA_USERNAME is not set. Extended info will not be loaded.");
+    match rooms::RoomCatalog::load(&config.rooms_path()) {
+        Ok(catalog) => rooms::init(catalog),
+        Err(e) => {
+            eprintln!("Failed to load room catalog: {e}");
+            std::process::exit(1);
+        }
     }
 
-    //let extended_info = load_extented_info(Ipv4Addr::new(172, 29, 4, 250)).await;
-    //println!("{:?}", extended_info);
-
-    // Restore state for all IPs
-    let mut states = restore_state(&data_dir).await;
-    for ip in generate_ips() {
-        states.entry(ip).or_default();
+    if config.username.is_none() && matches!(command, Command::Scan | Command::ScanOnce) {
+        eprintln!("INSA_USERNAME is not set. Extended info will not be loaded.");
     }
-    
-    update_stats(&states, &data_dir).await;
-    loop {
-        let now = Instant::now();
-        update(&mut states, &data_dir, &username).await;
-        update_stats(&states, &data_dir).await;
-        sleep(Duration::from_secs(600) - now.elapsed()).await;
+
+    let mut storage = match open_storage(&config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Failed to open storage: {e}");
+            std::process::exit(1);
+        }
+    };
+    let states = load_states(storage.as_mut(), &config).await;
+    let mut journal = match (config.journal.enabled, &command) {
+        (true, Command::Scan | Command::ScanOnce) => match Journal::open(&config.journal_path(), &states).await {
+            Ok(journal) => journal,
+            Err(e) => {
+                eprintln!("Failed to open journal: {e}");
+                std::process::exit(1);
+            }
+        },
+        _ => Journal::disabled(),
+    };
+    match command {
+        Command::Scan => {
+            let mut shutdown = listen_for_shutdown();
+            let fleet = identity::track(&states);
+            if let Err(e) = update_stats(&states, &fleet, &config, &Throttling::default()).await {
+                eprintln!("Failed to update stats: {e}");
+            }
+            let states = Arc::new(RwLock::new(states));
+            let fleet = Arc::new(RwLock::new(fleet));
+            if let Some(listen) = config.api.listen.clone() {
+                let (states, fleet) = (Arc::clone(&states), Arc::clone(&fleet));
+                tokio::spawn(async move {
+                    if let Err(e) = api::serve(&listen, states, fleet).await {
+                        eprintln!("{e}");
+                    }
+                });
+            }
+            let throttling = scan(&states, &fleet, storage.as_mut(), &mut journal, &config, false, &mut shutdown).await;
+            // Only returns on shutdown
+            if !final_save(&*states.read().await, storage.as_mut(), &mut journal, &config, &throttling).await {
+                std::process::exit(1);
+            }
+            println!("Shut down cleanly");
+        }
+        Command::ScanOnce => {
+            let mut shutdown = listen_for_shutdown();
+            let lock = RwLock::new(states);
+            // Nothing reads the machines tracked during the scan, final_save tracks them again
+            let throttling = scan(&lock, &RwLock::default(), storage.as_mut(), &mut journal, &config, true, &mut shutdown).await;
+            if !final_save(&lock.into_inner(), storage.as_mut(), &mut journal, &config, &throttling).await {
+                std::process::exit(1);
+            }
+        }
+        Command::Stats => {
+            if let Err(e) = update_stats(&states, &identity::track(&states), &config, &Throttling::default()).await {
+                eprintln!("Failed to update stats: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Site => {
+            if let Err(e) = update_site(&identity::track(&states), data_dir).await {
+                eprintln!("Failed to update site: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Inspect { ip } => {
+            let Some(state) = states.get(&ip) else {
+                eprintln!("{ip} is not part of the targets");
+                std::process::exit(1);
+            };
+            match storage.history(&states, ip, 0, now_utc()).await {
+                Ok(history) => {
+                    let leases = identity::track(&states).leases.remove(&ip).unwrap_or_default();
+                    inspect(ip, state, &history, &leases)
+                },
+                Err(e) => {
+                    eprintln!("Failed to load history of {ip}: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Recommend { count } => {
+            let recommendations = recommend::recommend(&identity::track(&states).machines, now_utc(), count);
+            if recommendations.is_empty() {
+                println!("No machine is up");
+            }
+            for r in recommendations {
+                let hostname = r.hostname.unwrap_or_else(|| r.ip.to_string());
+                println!(
+                    "{hostname} ({}): score {:.2}, up for {}, expected to stay up {} more, reliability {:.2}%, hardware {:.2}",
+                    r.ip,
+                    r.score,
+                    format_duration(r.breakdown.current_uptime_secs),
+                    format_duration(r.breakdown.predicted_remaining_secs),
+                    r.breakdown.reliability * 100.0,
+                    r.breakdown.hardware_score,
+                );
+            }
+        }
+        Command::Predict { at } => {
+            let now_utc = now_utc();
+            let at = at.unwrap_or(now_utc);
+            let predictor = Predictor::learn(&identity::track(&states).machines, now_utc);
+            println!("Predictions for {}", format_timestamp(at));
+            for room in rooms::catalog().names() {
+                if let Some(p) = predictor.room_up_probability(room, at) {
+                    println!("  {room}: {:.0}% of machines up", p * 100.0);
+                }
+            }
+            let mut machines: Vec<(Ipv4Addr, &MachineState)> = states.iter().map(|(ip, state)| (*ip, state)).collect();
+            machines.sort_by_key(|(ip, _)| *ip);
+            for (ip, state) in machines {
+                let Some(p) = predictor.up_probability(ip, at) else { continue };
+                let hostname = state.extended_info.as_ref().map(|info| info.hostname.clone()).unwrap_or_else(|| ip.to_string());
+                let shutdown = match predictor.expected_shutdown(ip, state, now_utc) {
+                    Some(shutdown) => format!(", expected to shut down at {}", format_timestamp(shutdown)),
+                    None => String::new(),
+                };
+                println!("  {hostname}: {:.0}% likely up{shutdown}", p * 100.0);
+            }
+        }
+        Command::Reassignments { since } => {
+            let since = since.unwrap_or(now_utc().saturating_sub(30*86400));
+            let reassignments = inventory::reassignments(&states, since);
+            if reassignments.is_empty() {
+                println!("No reassignment since {}", format_timestamp(since));
+            }
+            for reassignment in reassignments {
+                println!("{}  {reassignment}", format_timestamp(reassignment.time()));
+            }
+        }
+        Command::Migrate => {
+            if let Err(e) = storage.save_all(&states).await {
+                eprintln!("Failed to save states: {e}");
+                std::process::exit(1);
+            }
+            println!("Migrated {} machines", states.len());
+        }
+        Command::Replay => {
+            let mut states = match journal::replay(&config.journal_path()).await {
+                Ok(states) => states,
+                Err(e) => {
+                    eprintln!("Failed to replay journal: {e}");
+                    std::process::exit(1);
+                }
+            };
+            let ips = match config.targets.generate_ips() {
+                Ok(ips) => ips,
+                Err(e) => {
+                    eprintln!("Invalid targets: {e}");
+                    std::process::exit(1);
+                }
+            };
+            prune_to_targets(&mut states, ips);
+            if let Err(e) = storage.save_all(&states).await {
+                eprintln!("Failed to save states: {e}");
+                std::process::exit(1);
+            }
+            println!("Rebuilt {} machines from the journal", states.len());
+        }
     }
 }
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::identity::Fleet;
+use crate::rooms::room_of;
+use crate::schedule::{Lag, PRIORITIES};
+
+const PROBE_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 4.0, 12.0];
+const LATENESS_BUCKETS: [f64; 8] = [1.0, 10.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 14400.0];
+const SAVE_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+pub struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    buckets: [AtomicU64; N],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds: [f64; N]) -> Self {
+        Histogram {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {}", bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Counters updated by the scanner and exposed on `/metrics`
+pub struct Metrics {
+    pub probe_duration: Histogram<10>,
+    pub probes_up: AtomicU64,
+    pub probes_down: AtomicU64,
+    pub extended_info_successes: AtomicU64,
+    pub extended_info_failures: AtomicU64,
+    /// Time between the deadline of a machine and the start of its check
+    pub dispatch_lateness: Histogram<8>,
+    pub save_duration: Histogram<8>,
+    /// Per priority class, as of the last schedule report
+    pub overdue: [AtomicU64; 4],
+    pub max_lateness_secs: [AtomicU64; 4],
+    /// Bits of the current probe rate limit
+    probe_rate_limit: AtomicU64,
+    pub paused: AtomicU64,
+    /// Due machines that waited because their subnet had too many checks in flight
+    pub held_by_subnets: AtomicU64,
+    /// Machines checked during the last `period_secs`
+    pub period_checks: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    probe_duration: Histogram::new(PROBE_BUCKETS),
+    probes_up: AtomicU64::new(0),
+    probes_down: AtomicU64::new(0),
+    extended_info_successes: AtomicU64::new(0),
+    extended_info_failures: AtomicU64::new(0),
+    dispatch_lateness: Histogram::new(LATENESS_BUCKETS),
+    save_duration: Histogram::new(SAVE_BUCKETS),
+    overdue: [const { AtomicU64::new(0) }; 4],
+    max_lateness_secs: [const { AtomicU64::new(0) }; 4],
+    probe_rate_limit: AtomicU64::new(0),
+    paused: AtomicU64::new(0),
+    held_by_subnets: AtomicU64::new(0),
+    period_checks: AtomicU64::new(0),
+};
+
+impl Metrics {
+    pub fn set_probe_rate(&self, rate: f64) {
+        self.probe_rate_limit.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_schedule(&self, report: &[Lag; 4]) {
+        for (i, lag) in report.iter().enumerate() {
+            self.overdue[i].store(lag.overdue as u64, Ordering::Relaxed);
+            self.max_lateness_secs[i].store(lag.max_lateness_secs, Ordering::Relaxed);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Renders all metrics in the Prometheus text format, machine counts being those of the last save
+pub fn render(fleet: &Fleet, now_utc: u64) -> String {
+    let mut out = String::new();
+
+    // Same grouping as the site: machines that got another IP are counted once, in the room of their latest IP
+    let mut total_up_count = 0;
+    let mut total_machine_count = 0;
+    let mut per_room: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for machine in &fleet.machines {
+        let (ip, state) = (machine.ip(), &machine.state);
+        let (up, uptime, _) = state.times_since(now_utc.saturating_sub(30*86400), now_utc);
+        if up {
+            total_up_count += 1;
+        }
+        if uptime > 0 {
+            total_machine_count += 1;
+            let hostname = state.extended_info.as_ref().map(|info| info.hostname.as_str()).unwrap_or("");
+            let room = per_room.entry(room_of(ip, hostname)).or_default();
+            room.1 += 1;
+            if up {
+                room.0 += 1;
+            }
+        }
+    }
+
+    let _ = writeln!(out, "# HELP insa_scan_machines_up Machines currently up");
+    let _ = writeln!(out, "# TYPE insa_scan_machines_up gauge");
+    let _ = writeln!(out, "insa_scan_machines_up {total_up_count}");
+    let _ = writeln!(out, "# HELP insa_scan_machines_known Machines seen up during the last 30 days");
+    let _ = writeln!(out, "# TYPE insa_scan_machines_known gauge");
+    let _ = writeln!(out, "insa_scan_machines_known {total_machine_count}");
+    let _ = writeln!(out, "# HELP insa_scan_room_machines_up Machines currently up per room");
+    let _ = writeln!(out, "# TYPE insa_scan_room_machines_up gauge");
+    for (room, (up_count, _)) in &per_room {
+        let _ = writeln!(out, "insa_scan_room_machines_up{{room=\"{}\"}} {up_count}", escape_label(room));
+    }
+    let _ = writeln!(out, "# HELP insa_scan_room_machines_known Machines seen up during the last 30 days per room");
+    let _ = writeln!(out, "# TYPE insa_scan_room_machines_known gauge");
+    for (room, (_, machine_count)) in &per_room {
+        let _ = writeln!(out, "insa_scan_room_machines_known{{room=\"{}\"}} {machine_count}", escape_label(room));
+    }
+
+    let m = &METRICS;
+    m.probe_duration.render(&mut out, "insa_scan_probe_duration_seconds", "Duration of liveness probes");
+    let _ = writeln!(out, "# HELP insa_scan_probes_total Liveness probes by result");
+    let _ = writeln!(out, "# TYPE insa_scan_probes_total counter");
+    let _ = writeln!(out, "insa_scan_probes_total{{result=\"up\"}} {}", m.probes_up.load(Ordering::Relaxed));
+    let _ = writeln!(out, "insa_scan_probes_total{{result=\"down\"}} {}", m.probes_down.load(Ordering::Relaxed));
+    let _ = writeln!(out, "# HELP insa_scan_extended_info_total Extended info collections over SSH by result");
+    let _ = writeln!(out, "# TYPE insa_scan_extended_info_total counter");
+    let _ = writeln!(out, "insa_scan_extended_info_total{{result=\"success\"}} {}", m.extended_info_successes.load(Ordering::Relaxed));
+    let _ = writeln!(out, "insa_scan_extended_info_total{{result=\"failure\"}} {}", m.extended_info_failures.load(Ordering::Relaxed));
+    m.dispatch_lateness.render(&mut out, "insa_scan_dispatch_lateness_seconds", "Time between the check deadline of machines and the start of their check");
+    m.save_duration.render(&mut out, "insa_scan_save_duration_seconds", "Duration of states.bin saves");
+    let _ = writeln!(out, "# HELP insa_scan_probe_rate_limit Maximum number of probes started per second, lower during quiet hours");
+    let _ = writeln!(out, "# TYPE insa_scan_probe_rate_limit gauge");
+    let _ = writeln!(out, "insa_scan_probe_rate_limit {}", f64::from_bits(m.probe_rate_limit.load(Ordering::Relaxed)));
+    let _ = writeln!(out, "# HELP insa_scan_paused Whether probing is paused by the pause file");
+    let _ = writeln!(out, "# TYPE insa_scan_paused gauge");
+    let _ = writeln!(out, "insa_scan_paused {}", m.paused.load(Ordering::Relaxed));
+    let _ = writeln!(out, "# HELP insa_scan_held_by_subnets_total Times a due machine waited because its subnet had too many checks in flight");
+    let _ = writeln!(out, "# TYPE insa_scan_held_by_subnets_total counter");
+    let _ = writeln!(out, "insa_scan_held_by_subnets_total {}", m.held_by_subnets.load(Ordering::Relaxed));
+    let _ = writeln!(out, "# HELP insa_scan_period_checks Machines checked during the last period_secs");
+    let _ = writeln!(out, "# TYPE insa_scan_period_checks gauge");
+    let _ = writeln!(out, "insa_scan_period_checks {}", m.period_checks.load(Ordering::Relaxed));
+    let _ = writeln!(out, "# HELP insa_scan_overdue_machines Machines past their check deadline at the last schedule report, per priority class");
+    let _ = writeln!(out, "# TYPE insa_scan_overdue_machines gauge");
+    for (priority, overdue) in PRIORITIES.iter().zip(&m.overdue) {
+        let _ = writeln!(out, "insa_scan_overdue_machines{{priority=\"{}\"}} {}", priority.as_str(), overdue.load(Ordering::Relaxed));
+    }
+    let _ = writeln!(out, "# HELP insa_scan_max_lateness_seconds Time since the deadline of the most overdue machine at the last schedule report, per priority class");
+    let _ = writeln!(out, "# TYPE insa_scan_max_lateness_seconds gauge");
+    for (priority, lateness) in PRIORITIES.iter().zip(&m.max_lateness_secs) {
+        let _ = writeln!(out, "insa_scan_max_lateness_seconds{{priority=\"{}\"}} {}", priority.as_str(), lateness.load(Ordering::Relaxed));
+    }
+
+    out
+}
@@ -0,0 +1,393 @@
+//! Upgrades states.bin files written by older versions.
+//!
+//! When the layout of `MachineState` or `ExtendedInfo` changes, bump `CURRENT_VERSION`,
+//! copy the previous layout into a `vN` module below and add a conversion to the next version.
+
+use serde::de::DeserializeOwned;
+use crate::{ExtendedInfo, MachineState, States};
+use crate::probe::{ProbeDetails, ProbeStatus};
+use crate::ssh::{HostKey, SshHost};
+
+pub const CURRENT_VERSION: u32 = 7;
+
+/// Before probe results were recorded
+mod v1 {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct ExtendedInfo {
+        pub hostname: String,
+        pub cpuinfo: String,
+        pub meminfo: String,
+        pub ipaddr: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MachineState {
+        pub changes: Vec<u64>,
+        pub last_checked: u64,
+        pub extended_info: Option<ExtendedInfo>,
+    }
+
+    pub type States = HashMap<Ipv4Addr, MachineState>;
+}
+
+/// Before SSH host keys were recorded
+mod v2 {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Deserialize;
+    pub use super::v1::ExtendedInfo;
+
+    #[derive(Deserialize)]
+    pub enum ProbeStatus {
+        Up,
+        ServiceDown,
+        Down,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ProbeDetails {
+        pub kind: String,
+        pub port: Option<u16>,
+        pub elapsed_ms: u64,
+        pub outcome: String,
+        pub status: Option<ProbeStatus>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MachineState {
+        pub changes: Vec<u64>,
+        pub last_checked: u64,
+        pub extended_info: Option<ExtendedInfo>,
+        pub last_probe: Option<ProbeDetails>,
+    }
+
+    pub type States = HashMap<Ipv4Addr, MachineState>;
+}
+
+fn from_v1(states: v1::States) -> v2::States {
+    states.into_iter().map(|(ip, state)| {
+        (ip, v2::MachineState {
+            changes: state.changes,
+            last_checked: state.last_checked,
+            extended_info: state.extended_info,
+            last_probe: None,
+        })
+    }).collect()
+}
+
+/// Before hardware info was parsed at collection time
+mod v3 {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Deserialize;
+    pub use super::v1::ExtendedInfo;
+    pub use super::v2::{ProbeDetails, ProbeStatus};
+
+    #[derive(Deserialize)]
+    pub struct HostKey {
+        pub key_type: String,
+        pub fingerprint: String,
+        pub first_seen: u64,
+        pub last_seen: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct SshHost {
+        pub banner: String,
+        pub keys: Vec<HostKey>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MachineState {
+        pub changes: Vec<u64>,
+        pub last_checked: u64,
+        pub extended_info: Option<ExtendedInfo>,
+        pub last_probe: Option<ProbeDetails>,
+        pub ssh_host: Option<SshHost>,
+    }
+
+    pub type States = HashMap<Ipv4Addr, MachineState>;
+}
+
+fn from_v2(states: v2::States) -> v3::States {
+    states.into_iter().map(|(ip, state)| {
+        (ip, v3::MachineState {
+            changes: state.changes,
+            last_checked: state.last_checked,
+            extended_info: state.extended_info,
+            last_probe: state.last_probe,
+            ssh_host: None,
+        })
+    }).collect()
+}
+
+/// Before extended info was refreshed periodically
+mod v4 {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Deserialize;
+    // Unchanged since
+    pub use crate::hardware::{HardwareInfo, RawInfo};
+    pub use super::v3::{ProbeDetails, ProbeStatus, SshHost};
+
+    #[derive(Deserialize)]
+    pub struct ExtendedInfo {
+        pub hostname: String,
+        pub hardware: HardwareInfo,
+        pub raw: Option<Box<RawInfo>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MachineState {
+        pub changes: Vec<u64>,
+        pub last_checked: u64,
+        pub extended_info: Option<ExtendedInfo>,
+        pub last_probe: Option<ProbeDetails>,
+        pub ssh_host: Option<SshHost>,
+    }
+
+    pub type States = HashMap<Ipv4Addr, MachineState>;
+}
+
+fn from_v3(states: v3::States) -> v4::States {
+    states.into_iter().map(|(ip, state)| {
+        // The raw text is kept since it's all there was
+        let extended_info = state.extended_info.map(|info| {
+            let info = ExtendedInfo::from_legacy(info.hostname, info.cpuinfo, info.meminfo, info.ipaddr);
+            v4::ExtendedInfo { hostname: info.hostname, hardware: info.hardware, raw: info.raw }
+        });
+        (ip, v4::MachineState {
+            changes: state.changes,
+            last_checked: state.last_checked,
+            extended_info,
+            last_probe: state.last_probe,
+            ssh_host: state.ssh_host,
+        })
+    }).collect()
+}
+
+/// Before load was sampled
+mod v5 {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Deserialize;
+    // Unchanged since
+    pub use crate::hardware::{HardwareInfo, RawInfo};
+    pub use crate::probe::ProbeDetails;
+    pub use crate::ssh::SshHost;
+
+    #[derive(Deserialize)]
+    pub struct ExtendedInfo {
+        pub hostname: String,
+        pub hardware: HardwareInfo,
+        pub raw: Option<Box<RawInfo>>,
+        pub collected_at: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MachineState {
+        pub changes: Vec<u64>,
+        pub last_checked: u64,
+        pub extended_info: Option<ExtendedInfo>,
+        pub last_probe: Option<ProbeDetails>,
+        pub ssh_host: Option<SshHost>,
+        pub last_info_error: Option<(u64, String)>,
+    }
+
+    pub type States = HashMap<Ipv4Addr, MachineState>;
+}
+
+fn from_v4(states: v4::States) -> v5::States {
+    states.into_iter().map(|(ip, state)| {
+        // Unknown collection time, so that it gets refreshed
+        let extended_info = state.extended_info.map(|info| v5::ExtendedInfo {
+            hostname: info.hostname,
+            hardware: info.hardware,
+            raw: info.raw,
+            collected_at: 0,
+        });
+        let last_probe = state.last_probe.map(|probe| ProbeDetails {
+            kind: probe.kind,
+            port: probe.port,
+            elapsed_ms: probe.elapsed_ms,
+            outcome: probe.outcome,
+            status: probe.status.map(|status| match status {
+                v4::ProbeStatus::Up => ProbeStatus::Up,
+                v4::ProbeStatus::ServiceDown => ProbeStatus::ServiceDown,
+                v4::ProbeStatus::Down => ProbeStatus::Down,
+            }),
+        });
+        let ssh_host = state.ssh_host.map(|ssh| SshHost {
+            banner: ssh.banner,
+            keys: ssh.keys.into_iter().map(|key| HostKey {
+                key_type: key.key_type,
+                fingerprint: key.fingerprint,
+                first_seen: key.first_seen,
+                last_seen: key.last_seen,
+            }).collect(),
+        });
+        (ip, v5::MachineState {
+            changes: state.changes,
+            last_checked: state.last_checked,
+            extended_info,
+            last_probe,
+            ssh_host,
+            last_info_error: None,
+        })
+    }).collect()
+}
+
+/// Before extended info history was kept
+mod v6 {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Deserialize;
+    // Unchanged since
+    pub use crate::load::LoadSample;
+    pub use super::v5::{ExtendedInfo, ProbeDetails, SshHost};
+
+    #[derive(Deserialize)]
+    pub struct MachineState {
+        pub changes: Vec<u64>,
+        pub last_checked: u64,
+        pub extended_info: Option<ExtendedInfo>,
+        pub last_probe: Option<ProbeDetails>,
+        pub ssh_host: Option<SshHost>,
+        pub last_info_error: Option<(u64, String)>,
+        pub load: Vec<LoadSample>,
+    }
+
+    pub type States = HashMap<Ipv4Addr, MachineState>;
+}
+
+fn from_v5(states: v5::States) -> v6::States {
+    states.into_iter().map(|(ip, state)| {
+        (ip, v6::MachineState {
+            changes: state.changes,
+            last_checked: state.last_checked,
+            extended_info: state.extended_info,
+            last_probe: state.last_probe,
+            ssh_host: state.ssh_host,
+            last_info_error: state.last_info_error,
+            load: Vec::new(),
+        })
+    }).collect()
+}
+
+fn from_v6(states: v6::States) -> States {
+    states.into_iter().map(|(ip, state)| {
+        let extended_info = state.extended_info.map(|info| ExtendedInfo {
+            hostname: info.hostname,
+            hardware: info.hardware,
+            raw: info.raw,
+            collected_at: info.collected_at,
+            first_collected_at: info.collected_at,
+        });
+        (ip, MachineState {
+            changes: state.changes,
+            last_checked: state.last_checked,
+            extended_info,
+            last_probe: state.last_probe,
+            ssh_host: state.ssh_host,
+            last_info_error: state.last_info_error,
+            load: state.load,
+            info_history: Vec::new(),
+        })
+    }).collect()
+}
+
+fn deserialize<T: DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    bincode::deserialize(payload).map_err(|e| format!("Failed to deserialize: {e}"))
+}
+
+pub fn upgrade(version: u32, payload: &[u8]) -> Result<States, String> {
+    match version {
+        // Version 0 is the headerless format, its layout is the same as version 1
+        0 | 1 => deserialize(payload).map(from_v1).map(from_v2).map(from_v3).map(from_v4).map(from_v5).map(from_v6),
+        2 => deserialize(payload).map(from_v2).map(from_v3).map(from_v4).map(from_v5).map(from_v6),
+        3 => deserialize(payload).map(from_v3).map(from_v4).map(from_v5).map(from_v6),
+        4 => deserialize(payload).map(from_v4).map(from_v5).map(from_v6),
+        5 => deserialize(payload).map(from_v5).map(from_v6),
+        6 => deserialize(payload).map(from_v6),
+        7 => deserialize(payload),
+        _ => Err(format!("states.bin was written by a newer version of insa-scan (schema version {version}, this build supports up to {CURRENT_VERSION})")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use serde::Serialize;
+    use super::*;
+    use crate::persistence::{decode_states, encode_states};
+
+    // Layout of states.bin before versioning, which only the `Deserialize` side of the current types knows about
+    #[derive(Serialize)]
+    struct V0ExtendedInfo {
+        hostname: String,
+        cpuinfo: String,
+        meminfo: String,
+        ipaddr: String,
+    }
+
+    #[derive(Serialize)]
+    struct V0MachineState {
+        changes: Vec<u64>,
+        last_checked: u64,
+        extended_info: Option<V0ExtendedInfo>,
+    }
+
+    fn v0_file() -> Vec<u8> {
+        let info = V0ExtendedInfo {
+            hostname: String::from("if-501-01"),
+            cpuinfo: String::from("processor\t: 0\nmodel name\t: Intel(R) Core(TM) i5-6500\ncpu cores\t: 1\n\nprocessor\t: 1\nmodel name\t: Intel(R) Core(TM) i5-6500\ncpu cores\t: 1\n"),
+            meminfo: String::from("MemTotal:        8000000 kB\nSwapTotal:       1000000 kB\n"),
+            ipaddr: String::from("2: eno1: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500\n    link/ether 3c:ec:ef:12:34:56 brd ff:ff:ff:ff:ff:ff\n    inet 172.29.5.1/16 scope global eno1\n"),
+        };
+        let states = HashMap::from([
+            (Ipv4Addr::new(172, 29, 5, 1), V0MachineState { changes: vec![1000, 5000, 9000], last_checked: 9500, extended_info: Some(info) }),
+            (Ipv4Addr::new(172, 29, 5, 2), V0MachineState { changes: vec![1000, 1000], last_checked: 9500, extended_info: None }),
+        ]);
+        bincode::serialize(&states).unwrap()
+    }
+
+    #[test]
+    fn upgrades_headerless_file() {
+        let states = decode_states(&v0_file()).unwrap();
+        assert_eq!(states.len(), 2);
+
+        let state = &states[&Ipv4Addr::new(172, 29, 5, 1)];
+        assert_eq!((state.changes.as_slice(), state.last_checked), ([1000, 5000, 9000].as_slice(), 9500));
+        assert!(state.last_probe.is_none() && state.ssh_host.is_none() && state.last_info_error.is_none());
+        assert!(state.load.is_empty() && state.info_history.is_empty());
+        let info = state.extended_info.as_ref().unwrap();
+        assert_eq!(info.hostname, "if-501-01");
+        assert_eq!(info.hardware.cpu_model.as_deref(), Some("Intel(R) Core(TM) i5-6500"));
+        assert_eq!((info.hardware.threads, info.hardware.ram), (Some(2), Some(8_000_000_000)));
+        assert_eq!(info.hardware.mac(), Some("3c:ec:ef:12:34:56"));
+        // Unknown collection time, so that it gets refreshed
+        assert_eq!((info.collected_at, info.first_collected_at), (0, 0));
+        assert!(info.raw.as_ref().is_some_and(|raw| raw.ip_addr.contains("172.29.5.1/16")));
+
+        assert!(states[&Ipv4Addr::new(172, 29, 5, 2)].extended_info.is_none());
+    }
+
+    #[test]
+    fn upgraded_states_are_written_in_current_version() {
+        let states = decode_states(&v0_file()).unwrap();
+        let file = encode_states(&states).unwrap();
+        assert_eq!(&file[8..12], CURRENT_VERSION.to_le_bytes().as_slice());
+        let again = decode_states(&file).unwrap();
+        assert_eq!(again[&Ipv4Addr::new(172, 29, 5, 1)].changes, [1000, 5000, 9000]);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        assert!(upgrade(CURRENT_VERSION + 1, &[]).is_err());
+    }
+}
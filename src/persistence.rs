@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use crate::{now_utc, States};
+use crate::config::PersistenceConfig;
+use crate::error::{with_retries, Error};
+use crate::metrics::METRICS;
+use crate::migrations::{self, CURRENT_VERSION};
+
+/// Every states.bin written since schema versioning starts with these bytes.
+/// Older files are raw bincode dumps and are considered to be version 0.
+const MAGIC: &[u8; 8] = b"INSASCAN";
+
+pub fn encode_states(states: &States) -> Result<Vec<u8>, Error> {
+    let payload = bincode::serialize(states).map_err(|e| Error::Format(format!("Failed to serialize states: {e}")))?;
+    let mut file = Vec::with_capacity(MAGIC.len() + 4 + payload.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&CURRENT_VERSION.to_le_bytes());
+    file.extend_from_slice(&payload);
+    Ok(file)
+}
+
+/// Returns the schema version of a states file and its payload
+fn split_header(file: &[u8]) -> Result<(u32, &[u8]), String> {
+    match file.strip_prefix(MAGIC) {
+        Some(rest) => {
+            let version = rest.get(..4).ok_or("Truncated header")?;
+            let version = u32::from_le_bytes(version.try_into().unwrap_or_default());
+            Ok((version, &rest[4..]))
+        }
+        None => Ok((0, file)),
+    }
+}
+
+pub fn decode_states(file: &[u8]) -> Result<States, String> {
+    let (version, payload) = split_header(file)?;
+    migrations::upgrade(version, payload)
+}
+
+fn backup_dir(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join("backups")
+}
+
+/// Lists backups as (timestamp, path), newest first
+async fn list_backups(data_dir: &str) -> Vec<(u64, PathBuf)> {
+    let mut backups = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(backup_dir(data_dir)).await else { return backups };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let Some(timestamp) = name.to_str()
+            .and_then(|name| name.strip_prefix("states-"))
+            .and_then(|name| name.strip_suffix(".bin"))
+            .and_then(|timestamp| timestamp.parse().ok()) else { continue };
+        backups.push((timestamp, entry.path()));
+    }
+    backups.sort_by(|(t1, _), (t2, _)| t2.cmp(t1));
+    backups
+}
+
+/// Writes to a temporary file that is fsynced and renamed over the destination, so that the destination is never left half-written
+async fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    write_atomic_once(path, content).await.map_err(|e| Error::io(path, e))
+}
+
+async fn write_atomic_once(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    // Persist the rename itself
+    if let Some(parent) = path.parent() {
+        let dir = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+pub async fn restore_state(data_dir: &str) -> Result<States, Error> {
+    let path = Path::new(data_dir).join("states.bin");
+    let read = with_retries("read states.bin", || async {
+        tokio::fs::read(&path).await.map_err(|e| Error::io(&path, e))
+    }).await;
+    let file: Vec<u8> = match read {
+        Ok(file) => file,
+        Err(Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => return Ok(States::new()),
+        // The file may be fine, so it is left for the operator to fix rather than replaced by older data
+        Err(e) => return Err(e),
+    };
+
+    // Never fall back to an older backup when the file is fine but too recent for us, it would be overwritten on next save
+    let (version, _) = split_header(&file).unwrap_or((0, &[]));
+    if version > CURRENT_VERSION {
+        return Err(Error::TooRecent(version));
+    }
+
+    match decode_states(&file) {
+        Ok(states) => Ok(states),
+        Err(e) => restore_backup(data_dir, format!("Failed to load states.bin: {e}")).await,
+    }
+}
+
+/// Loads the newest backup that can be deserialized
+async fn restore_backup(data_dir: &str, error: String) -> Result<States, Error> {
+    eprintln!("{error}. Trying backups.");
+    for (_, path) in list_backups(data_dir).await {
+        let states = match tokio::fs::read(&path).await {
+            Ok(file) => decode_states(&file),
+            Err(e) => Err(e.to_string()),
+        };
+        match states {
+            Ok(states) => {
+                eprintln!("Restored backup {}", path.display());
+                return Ok(states);
+            }
+            Err(e) => eprintln!("Backup {} is unusable: {e}", path.display()),
+        }
+    }
+    Err(Error::Format(format!("{error} and no valid backup was found")))
+}
+
+/// Writes states.bin, and a backup if the last one is old enough.
+/// Transient errors, like a full disk, are retried a few times before giving up.
+pub async fn save_states(states: &States, data_dir: &str, config: &PersistenceConfig) -> Result<(), Error> {
+    let start = Instant::now();
+    let file = encode_states(states)?;
+    let path = Path::new(data_dir).join("states.bin");
+    with_retries("write states.bin", || write_atomic(&path, &file)).await?;
+    METRICS.save_duration.observe(start.elapsed());
+
+    if config.backups == 0 {
+        return Ok(());
+    }
+    let now_utc = now_utc();
+    let backups = list_backups(data_dir).await;
+    if backups.first().is_some_and(|(timestamp, _)| now_utc < timestamp + config.backup_interval_secs) {
+        return Ok(());
+    }
+    tokio::fs::create_dir_all(backup_dir(data_dir)).await.map_err(|e| Error::io(backup_dir(data_dir), e))?;
+    let path = backup_dir(data_dir).join(format!("states-{now_utc}.bin"));
+    with_retries("write backup", || write_atomic(&path, &file)).await?;
+    for (_, path) in backups.into_iter().skip(config.backups - 1) {
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            eprintln!("Failed to remove old backup {}: {e}", path.display());
+        }
+    }
+    Ok(())
+}
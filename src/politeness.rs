@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use progress_bar::{global::*, Color, Style};
+use crate::config::Config;
+use crate::metrics::METRICS;
+
+/// How often the pause file and quiet hours are looked at
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Allows `rate` connection attempts per second on average, and bursts of up to `capacity`
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> TokenBucket {
+        TokenBucket { rate, capacity, tokens: capacity, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.refill();
+        self.rate = rate;
+    }
+
+    /// More tokens than the capacity are never available at once, so asking for more only takes the capacity
+    fn cost(&self, count: usize) -> f64 {
+        (count as f64).min(self.capacity)
+    }
+
+    /// Takes `count` tokens, one per connection, if there are enough
+    pub fn try_take(&mut self, count: usize) -> bool {
+        self.refill();
+        let cost = self.cost(count);
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+
+    /// Gives back tokens that weren't used
+    pub fn put_back(&mut self, count: usize) {
+        self.tokens = (self.tokens + self.cost(count)).min(self.capacity);
+    }
+
+    /// When `count` tokens will be available
+    pub fn next_tokens(&mut self, count: usize) -> Instant {
+        self.refill();
+        self.last + Duration::from_secs_f64((self.cost(count) - self.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Handle on the token bucket for the connections checks open after their probe, such as SSH
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    fn bucket(&self) -> std::sync::MutexGuard<'_, TokenBucket> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits for the token of a connection
+    pub async fn acquire(&self) {
+        loop {
+            let next = {
+                let mut bucket = self.bucket();
+                if bucket.try_take(1) {
+                    return;
+                }
+                bucket.next_tokens(1)
+            };
+            tokio::time::sleep_until(next.into()).await;
+        }
+    }
+}
+
+/// What politeness did, for stats.csv
+#[derive(Debug, Default, Clone)]
+pub struct Throttling {
+    pub paused: bool,
+    /// Quiet hours lower the rate limit
+    pub quiet: bool,
+    /// Times the start of the check of each machine was held back by subnet caps, since startup
+    pub deferred: HashMap<Ipv4Addr, u64>,
+}
+
+/// Decides when the next probe may start: rate limit, quiet hours, per-subnet caps and pause
+pub struct Politeness<'a> {
+    config: &'a Config,
+    limiter: RateLimiter,
+    /// Checks in flight per subnet
+    in_flight: HashMap<u32, usize>,
+    pause_file: PathBuf,
+    throttling: Throttling,
+    last_poll: Option<Instant>,
+}
+
+impl<'a> Politeness<'a> {
+    pub fn new(config: &'a Config) -> Politeness<'a> {
+        let bucket = TokenBucket::new(config.scan.probes_per_sec, config.politeness.burst as f64);
+        let limiter = RateLimiter { bucket: Arc::new(Mutex::new(bucket)) };
+        let pause_file = std::path::Path::new(&config.data_dir).join(&config.politeness.pause_file);
+        METRICS.set_probe_rate(config.scan.probes_per_sec);
+        Politeness { config, limiter, in_flight: HashMap::new(), pause_file, throttling: Throttling::default(), last_poll: None }
+    }
+
+    /// Looks at the pause file and quiet hours, logging changes
+    pub fn poll(&mut self, now_utc: u64) {
+        if self.last_poll.is_some_and(|last| last.elapsed() < POLL_INTERVAL) {
+            return;
+        }
+        self.last_poll = Some(Instant::now());
+
+        let paused = self.pause_file.exists();
+        if paused != self.throttling.paused {
+            match paused {
+                true => print_progress_bar_info("Paused", &format!("probing until {} is removed", self.pause_file.display()), Color::Yellow, Style::Bold),
+                false => print_progress_bar_info("Resumed", "probing", Color::Green, Style::Bold),
+            }
+            self.throttling.paused = paused;
+            METRICS.paused.store(paused as u64, Ordering::Relaxed);
+        }
+
+        let quiet = self.config.politeness.quiet_hours.iter().any(|hours| hours.contains(now_utc));
+        if quiet != self.throttling.quiet {
+            let rate = self.rate_limit_for(quiet);
+            match quiet {
+                true => print_progress_bar_info("Quiet", &format!("hours started, probing at most {rate} machines per second"), Color::Yellow, Style::Bold),
+                false => print_progress_bar_info("Quiet", &format!("hours are over, probing at most {rate} machines per second"), Color::Green, Style::Bold),
+            }
+            self.throttling.quiet = quiet;
+            self.limiter.bucket().set_rate(rate);
+            METRICS.set_probe_rate(rate);
+        }
+    }
+
+    fn rate_limit_for(&self, quiet: bool) -> f64 {
+        match quiet {
+            true => self.config.politeness.quiet_probes_per_sec,
+            false => self.config.scan.probes_per_sec,
+        }
+    }
+
+    pub fn rate_limit(&self) -> f64 {
+        self.rate_limit_for(self.throttling.quiet)
+    }
+
+    pub fn paused(&self) -> bool {
+        self.throttling.paused
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.throttling.quiet
+    }
+
+    pub fn throttling(&self) -> &Throttling {
+        &self.throttling
+    }
+
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    fn subnet(&self, ip: Ipv4Addr) -> u32 {
+        match self.config.politeness.subnet_prefix {
+            0 => 0,
+            prefix => u32::from(ip) >> (32 - prefix as u32),
+        }
+    }
+
+    /// Whether the subnet of a machine is below its cap of checks in flight
+    pub fn subnet_has_room(&self, ip: Ipv4Addr) -> bool {
+        self.in_flight.get(&self.subnet(ip)).copied().unwrap_or(0) < self.config.politeness.max_per_subnet
+    }
+
+    /// Takes the tokens of the connections of a probe, if there are enough
+    pub fn try_start(&mut self, connections: usize) -> bool {
+        !self.throttling.paused && self.limiter.bucket().try_take(connections)
+    }
+
+    /// Gives back the tokens of a probe that couldn't start
+    pub fn refund(&mut self, connections: usize) {
+        self.limiter.bucket().put_back(connections);
+    }
+
+    /// Records that the check of a machine was held back by its subnet cap
+    pub fn deferred(&mut self, ip: Ipv4Addr) {
+        *self.throttling.deferred.entry(ip).or_default() += 1;
+    }
+
+    pub fn started(&mut self, ip: Ipv4Addr) {
+        *self.in_flight.entry(self.subnet(ip)).or_default() += 1;
+    }
+
+    pub fn finished(&mut self, ip: Ipv4Addr) {
+        let subnet = self.subnet(ip);
+        if let Some(count) = self.in_flight.get_mut(&subnet) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&subnet);
+            }
+        }
+    }
+
+    /// When it's worth trying to start a probe again
+    pub fn next_start(&mut self) -> Instant {
+        match self.throttling.paused {
+            true => Instant::now() + POLL_INTERVAL,
+            false => self.limiter.bucket().next_tokens(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretends the last refill happened `secs` earlier
+    fn wait(bucket: &mut TokenBucket, secs: f64) {
+        bucket.last -= Duration::from_secs_f64(secs);
+    }
+
+    #[test]
+    fn starts_full_and_empties() {
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        assert!(bucket.try_take(2));
+        assert!(bucket.try_take(1));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn refills_at_rate_up_to_capacity() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        assert!(bucket.try_take(3));
+        wait(&mut bucket, 1.0);
+        assert!(bucket.try_take(2));
+        assert!(!bucket.try_take(1));
+        wait(&mut bucket, 60.0);
+        assert!(bucket.try_take(3));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn refund_gives_tokens_back() {
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        assert!(bucket.try_take(3));
+        bucket.put_back(2);
+        assert!(bucket.try_take(2));
+        assert!(!bucket.try_take(1));
+        // Refunds never overfill
+        bucket.put_back(10);
+        assert!(bucket.try_take(3));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn more_than_capacity_takes_capacity() {
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        assert!(bucket.try_take(5));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn next_tokens_when_empty() {
+        let mut bucket = TokenBucket::new(4.0, 2.0);
+        assert!(bucket.try_take(2));
+        let wait = bucket.next_tokens(2).duration_since(bucket.last);
+        assert!((wait.as_secs_f64() - 0.5).abs() < 0.01, "{wait:?}");
+        assert!(bucket.next_tokens(0) <= Instant::now());
+    }
+}
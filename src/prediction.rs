@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use chrono::{Datelike, Timelike};
+use crate::MachineState;
+use crate::identity::TrackedMachine;
+use crate::rooms::room_of;
+
+const HOURS_PER_WEEK: usize = 7*24;
+/// History older than this is ignored
+const LEARNING_WINDOW_SECS: u64 = 8*7*86400;
+/// Below this amount of observation for an hour of the week, the room pattern is used instead of the machine's
+const MIN_OBSERVED_SECS: f64 = 2.0 * 3600.0;
+/// A machine is expected to shut down at the first hour it is more likely to be down than up
+const SHUTDOWN_THRESHOLD: f64 = 0.5;
+
+/// Hour of the week and seconds into that hour, Monday 00:00 being 0.
+/// Uses the local time of the scanner, like the opening hours of rooms, so patterns follow the timetable across DST changes.
+fn hour_of_week(time_utc: u64) -> (usize, u64) {
+    let Some(time) = chrono::DateTime::from_timestamp(time_utc as i64, 0) else { return (0, 0) };
+    let time = time.with_timezone(&chrono::Local);
+    let hour = time.weekday().num_days_from_monday() as usize * 24 + time.hour() as usize;
+    (hour, (time.minute() * 60 + time.second()) as u64)
+}
+
+/// How much time a machine (or a room) spent up for each hour of the week
+#[derive(Clone)]
+pub struct WeeklyPattern {
+    up: [f64; HOURS_PER_WEEK],
+    observed: [f64; HOURS_PER_WEEK],
+}
+
+impl Default for WeeklyPattern {
+    fn default() -> Self {
+        WeeklyPattern {
+            up: [0.0; HOURS_PER_WEEK],
+            observed: [0.0; HOURS_PER_WEEK],
+        }
+    }
+}
+
+impl WeeklyPattern {
+    pub fn learn(state: &MachineState, now_utc: u64) -> WeeklyPattern {
+        let mut pattern = WeeklyPattern::default();
+        let since = now_utc.saturating_sub(LEARNING_WINDOW_SECS);
+        for (i, start) in state.changes.iter().enumerate() {
+            let end = state.changes.get(i + 1).copied().unwrap_or(now_utc);
+            pattern.add(std::cmp::max(*start, since), std::cmp::min(end, now_utc), i % 2 == 0);
+        }
+        pattern
+    }
+
+    /// Spreads a period over the hours of the week it covers
+    fn add(&mut self, start: u64, end: u64, up: bool) {
+        let mut time = start;
+        while time < end {
+            let (hour, into_hour) = hour_of_week(time);
+            let next_hour = time - into_hour + 3600;
+            let segment_end = std::cmp::min(next_hour, end);
+            self.observed[hour] += (segment_end - time) as f64;
+            if up {
+                self.up[hour] += (segment_end - time) as f64;
+            }
+            time = segment_end;
+        }
+    }
+
+    fn merge(&mut self, other: &WeeklyPattern) {
+        for hour in 0..HOURS_PER_WEEK {
+            self.up[hour] += other.up[hour];
+            self.observed[hour] += other.observed[hour];
+        }
+    }
+
+    /// Probability of being up at that time of the week, if it was observed enough
+    pub fn probability(&self, time_utc: u64) -> Option<f64> {
+        let (hour, _) = hour_of_week(time_utc);
+        (self.observed[hour] >= MIN_OBSERVED_SECS).then(|| self.up[hour] / self.observed[hour])
+    }
+}
+
+/// Weekly patterns of every known machine and room
+pub struct Predictor {
+    machines: HashMap<Ipv4Addr, (WeeklyPattern, &'static str)>,
+    rooms: HashMap<&'static str, WeeklyPattern>,
+}
+
+impl Predictor {
+    pub fn learn(tracked: &[TrackedMachine], now_utc: u64) -> Predictor {
+        let mut machines = HashMap::new();
+        let mut rooms: HashMap<&'static str, WeeklyPattern> = HashMap::new();
+        // Machines that got another IP are learned from their whole history, under their latest IP
+        for machine in tracked {
+            let state = &machine.state;
+            let (up, uptime, _) = state.times_since(0, now_utc);
+            if !up && uptime == 0 {
+                continue;
+            }
+            let ip = machine.ip();
+            let hostname = state.extended_info.as_ref().map(|info| info.hostname.as_str()).unwrap_or("");
+            let room = room_of(ip, hostname);
+            let pattern = WeeklyPattern::learn(state, now_utc);
+            rooms.entry(room).or_default().merge(&pattern);
+            // The latest IP of a machine that left can be used by another one
+            let holds_ip = machine.leases.iter().any(|lease| lease.ip == ip && lease.to.is_none());
+            if holds_ip || !machines.contains_key(&ip) {
+                machines.insert(ip, (pattern, room));
+            }
+        }
+        Predictor { machines, rooms }
+    }
+
+    /// Probability that a machine is up at a given time, falling back to the pattern of its room
+    pub fn up_probability(&self, ip: Ipv4Addr, time_utc: u64) -> Option<f64> {
+        let (pattern, room) = self.machines.get(&ip)?;
+        pattern.probability(time_utc).or_else(|| self.room_up_probability(room, time_utc))
+    }
+
+    pub fn room_up_probability(&self, room: &str, time_utc: u64) -> Option<f64> {
+        self.rooms.get(room)?.probability(time_utc)
+    }
+
+    /// First time within a week at which a machine that is up is more likely to be down.
+    /// None if the machine is down, or expected to stay up all week.
+    pub fn expected_shutdown(&self, ip: Ipv4Addr, state: &MachineState, now_utc: u64) -> Option<u64> {
+        if !state.up() {
+            return None;
+        }
+        let (_, into_hour) = hour_of_week(now_utc);
+        (0..HOURS_PER_WEEK as u64)
+            .map(|i| if i == 0 { now_utc } else { now_utc - into_hour + i * 3600 })
+            .find(|time| self.up_probability(ip, *time).is_some_and(|p| p < SHUTDOWN_THRESHOLD))
+    }
+}
@@ -0,0 +1,291 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::{Serialize, Deserialize};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+use crate::targets::TargetRange;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeStatus {
+    /// The service answered
+    Up,
+    /// The host answered but the service didn't (connection refused, no SSH banner...)
+    ServiceDown,
+    /// Nothing answered
+    Down,
+}
+
+impl ProbeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeStatus::Up => "up",
+            ProbeStatus::ServiceDown => "service_down",
+            ProbeStatus::Down => "down",
+        }
+    }
+}
+
+/// What a probe observed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProbeDetails {
+    /// Journals written before probes were configurable only contain TCP probes
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    pub port: Option<u16>,
+    pub elapsed_ms: u64,
+    /// `connected`, `timeout`, the connection error, the SSH banner...
+    pub outcome: String,
+    /// Unknown when the probe couldn't run, and in journals written before statuses
+    #[serde(default)]
+    pub status: Option<ProbeStatus>,
+}
+
+fn default_kind() -> String {
+    String::from("tcp")
+}
+
+impl ProbeDetails {
+    pub fn up(&self) -> bool {
+        self.status == Some(ProbeStatus::Up)
+    }
+}
+
+/// A way to tell whether a machine is up
+#[async_trait]
+pub trait Probe: Send + Sync {
+    async fn probe(&self, ip: Ipv4Addr, time_to_wait: Duration) -> ProbeDetails;
+
+    /// Connection attempts a probe makes, each one taking a token of the rate limit
+    fn connections(&self) -> usize {
+        1
+    }
+}
+
+/// Classifies the result of a TCP connection attempt
+fn connect_status(r: &Result<std::io::Result<TcpStream>, tokio::time::error::Elapsed>) -> (ProbeStatus, String) {
+    match r {
+        Ok(Ok(_)) => (ProbeStatus::Up, String::from("connected")),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => (ProbeStatus::ServiceDown, e.to_string()),
+        Ok(Err(e)) => (ProbeStatus::Down, e.to_string()),
+        Err(_) => (ProbeStatus::Down, String::from("timeout")),
+    }
+}
+
+async fn connect(ip: Ipv4Addr, port: u16, time_to_wait: Duration) -> Result<std::io::Result<TcpStream>, tokio::time::error::Elapsed> {
+    timeout(time_to_wait, TcpStream::connect(SocketAddr::new(IpAddr::V4(ip), port))).await
+}
+
+/// Up if a TCP connection can be established
+pub struct TcpProbe {
+    pub port: u16,
+}
+
+#[async_trait]
+impl Probe for TcpProbe {
+    async fn probe(&self, ip: Ipv4Addr, time_to_wait: Duration) -> ProbeDetails {
+        let start = Instant::now();
+        let r = connect(ip, self.port, time_to_wait).await;
+        let (status, outcome) = connect_status(&r);
+        ProbeDetails {
+            kind: String::from("tcp"),
+            port: Some(self.port),
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            outcome,
+            status: Some(status),
+        }
+    }
+}
+
+/// Up if the server sends an SSH identification string (`SSH-2.0-...`)
+pub struct SshBannerProbe {
+    pub port: u16,
+}
+
+/// Reads the first line sent by an SSH server
+pub async fn read_banner(stream: &mut TcpStream) -> Result<String, String> {
+    let mut banner = Vec::new();
+    let mut byte = [0; 1];
+    while banner.len() < 255 {
+        match stream.read(&mut byte).await {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => banner.push(byte[0]),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let banner = String::from_utf8_lossy(&banner).trim_end().to_string();
+    match banner.starts_with("SSH-") {
+        true => Ok(banner),
+        false => Err(format!("unexpected banner {banner:?}")),
+    }
+}
+
+#[async_trait]
+impl Probe for SshBannerProbe {
+    async fn probe(&self, ip: Ipv4Addr, time_to_wait: Duration) -> ProbeDetails {
+        let start = Instant::now();
+        let (status, outcome) = match connect(ip, self.port, time_to_wait).await {
+            Ok(Ok(mut stream)) => {
+                // The host answered, so failing to get a banner means sshd is not working
+                match timeout(time_to_wait.saturating_sub(start.elapsed()), read_banner(&mut stream)).await {
+                    Ok(Ok(banner)) => (ProbeStatus::Up, banner),
+                    Ok(Err(e)) => (ProbeStatus::ServiceDown, e),
+                    Err(_) => (ProbeStatus::ServiceDown, String::from("no banner before timeout")),
+                }
+            }
+            r => connect_status(&r),
+        };
+        ProbeDetails {
+            kind: String::from("ssh_banner"),
+            port: Some(self.port),
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            outcome,
+            status: Some(status),
+        }
+    }
+}
+
+/// Up if the host answers an ICMP echo request.
+/// Relies on the `ping` binary, which has the privileges to send them.
+pub struct IcmpProbe;
+
+#[async_trait]
+impl Probe for IcmpProbe {
+    async fn probe(&self, ip: Ipv4Addr, time_to_wait: Duration) -> ProbeDetails {
+        let start = Instant::now();
+        let seconds = time_to_wait.as_secs().max(1);
+        let ping = Command::new("ping")
+            .args(["-c", "1", "-W", &seconds.to_string(), &ip.to_string()])
+            .kill_on_drop(true)
+            .output();
+        // Failing to run ping, such as when out of file descriptors, tells nothing about the host
+        let (status, outcome) = match timeout(time_to_wait + Duration::from_secs(1), ping).await {
+            Ok(Ok(output)) if output.status.success() => (Some(ProbeStatus::Up), String::from("echo reply")),
+            Ok(Ok(output)) => {
+                let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+                (Some(ProbeStatus::Down), text.lines().last().unwrap_or("no reply").to_string())
+            },
+            Ok(Err(e)) => (None, format!("failed to run ping: {e}")),
+            Err(_) => (Some(ProbeStatus::Down), String::from("timeout")),
+        };
+        ProbeDetails {
+            kind: String::from("icmp"),
+            port: None,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            outcome,
+            status,
+        }
+    }
+}
+
+/// TCP connections to several ports at once
+pub struct MultiPortProbe {
+    pub ports: Vec<u16>,
+    /// Only up if all ports accept connections, instead of any of them
+    pub require_all: bool,
+}
+
+#[async_trait]
+impl Probe for MultiPortProbe {
+    async fn probe(&self, ip: Ipv4Addr, time_to_wait: Duration) -> ProbeDetails {
+        let start = Instant::now();
+        let results = join_all(self.ports.iter().map(|port| connect(ip, *port, time_to_wait))).await;
+        let statuses: Vec<(ProbeStatus, String)> = results.iter().map(connect_status).collect();
+        let up_count = statuses.iter().filter(|(status, _)| *status == ProbeStatus::Up).count();
+        let status = if (self.require_all && up_count == self.ports.len()) || (!self.require_all && up_count > 0) {
+            ProbeStatus::Up
+        } else if statuses.iter().any(|(status, _)| *status != ProbeStatus::Down) {
+            ProbeStatus::ServiceDown
+        } else {
+            ProbeStatus::Down
+        };
+        let outcome = self.ports.iter().zip(&statuses).map(|(port, (_, outcome))| format!("{port}: {outcome}")).collect::<Vec<_>>().join(", ");
+        ProbeDetails {
+            kind: String::from("multi_port"),
+            port: None,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            outcome,
+            status: Some(status),
+        }
+    }
+
+    fn connections(&self) -> usize {
+        self.ports.len()
+    }
+}
+
+/// Probe settings as found in the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ProbeKind {
+    Tcp {
+        port: u16,
+    },
+    SshBanner {
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+    },
+    Icmp,
+    MultiPort {
+        ports: Vec<u16>,
+        #[serde(default)]
+        require_all: bool,
+    },
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+impl Default for ProbeKind {
+    fn default() -> Self {
+        ProbeKind::Tcp { port: 22 }
+    }
+}
+
+impl ProbeKind {
+    pub fn build(&self) -> Box<dyn Probe> {
+        match self {
+            ProbeKind::Tcp { port } => Box::new(TcpProbe { port: *port }),
+            ProbeKind::SshBanner { port } => Box::new(SshBannerProbe { port: *port }),
+            ProbeKind::Icmp => Box::new(IcmpProbe),
+            ProbeKind::MultiPort { ports, require_all } => Box::new(MultiPortProbe { ports: ports.clone(), require_all: *require_all }),
+        }
+    }
+}
+
+/// Uses a different probe for some target ranges
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeRule {
+    pub targets: Vec<TargetRange>,
+    pub probe: ProbeKind,
+}
+
+/// Picks the probe of the first rule matching an IP, or the default one
+pub struct Probes {
+    rules: Vec<(Vec<TargetRange>, Box<dyn Probe>)>,
+    default: Box<dyn Probe>,
+}
+
+impl Probes {
+    pub fn new(default: &ProbeKind, rules: &[ProbeRule]) -> Probes {
+        Probes {
+            rules: rules.iter().map(|rule| (rule.targets.clone(), rule.probe.build())).collect(),
+            default: default.build(),
+        }
+    }
+
+    pub fn for_ip(&self, ip: Ipv4Addr) -> &dyn Probe {
+        self.rules.iter()
+            .find(|(targets, _)| targets.iter().any(|range| range.contains(ip)))
+            .map(|(_, probe)| probe.as_ref())
+            .unwrap_or(self.default.as_ref())
+    }
+}
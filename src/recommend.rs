@@ -0,0 +1,96 @@
+use std::net::Ipv4Addr;
+use serde::Serialize;
+use crate::MachineState;
+use crate::identity::TrackedMachine;
+
+/// Predicted remaining uptime at which the uptime score reaches 0.5
+const UPTIME_HALF_SCORE_SECS: f64 = 4.0 * 3600.0;
+/// Cap on the prediction for machines that are already up longer than they have ever been
+const MAX_EXTRAPOLATED_SECS: u64 = 86400;
+const UPTIME_WEIGHT: f64 = 0.6;
+const RELIABILITY_WEIGHT: f64 = 0.25;
+const HARDWARE_WEIGHT: f64 = 0.15;
+
+#[derive(Debug, Serialize)]
+pub struct Recommendation {
+    /// IP of the latest lease
+    pub ip: Ipv4Addr,
+    pub hostname: Option<String>,
+    pub score: f64,
+    pub breakdown: ScoreBreakdown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScoreBreakdown {
+    /// Time since the machine came up
+    pub current_uptime_secs: u64,
+    /// Expected time before it goes down
+    pub predicted_remaining_secs: u64,
+    /// Between 0 and 1, derived from `predicted_remaining_secs`
+    pub uptime_score: f64,
+    /// Share of the last 30 days the machine was up
+    pub reliability: f64,
+    /// Between 0 and 1, RAM and thread count relative to the best machine of the fleet
+    pub hardware_score: f64,
+}
+
+/// Durations of past up periods, ignoring the one in progress
+fn past_uptimes(state: &MachineState) -> Vec<u64> {
+    state.changes.chunks_exact(2).map(|pair| pair[1] - pair[0]).filter(|duration| *duration > 0).collect()
+}
+
+/// Mean remaining life of past up periods that lasted longer than the current one.
+/// Machines already up longer than ever before are expected to stay up as long again, up to a cap.
+fn predict_remaining(state: &MachineState, current_uptime: u64) -> u64 {
+    let longer: Vec<u64> = past_uptimes(state).into_iter().filter(|duration| *duration > current_uptime).collect();
+    if longer.is_empty() {
+        return current_uptime.min(MAX_EXTRAPOLATED_SECS);
+    }
+    longer.iter().map(|duration| duration - current_uptime).sum::<u64>() / longer.len() as u64
+}
+
+/// Ranks machines that are currently up by how long they are expected to stay up, how reliable they are and their hardware.
+/// Machines that got another IP are ranked once, with their whole history.
+pub fn recommend(tracked: &[TrackedMachine], now_utc: u64, count: usize) -> Vec<Recommendation> {
+    let max_ram = tracked.iter().filter_map(|machine| machine.state.extended_info.as_ref()?.hardware.ram).max().unwrap_or(0);
+    let max_threads = tracked.iter().filter_map(|machine| machine.state.extended_info.as_ref()?.hardware.threads).max().unwrap_or(0);
+
+    let mut recommendations: Vec<Recommendation> = tracked.iter()
+        .filter(|machine| machine.state.up())
+        .map(|machine| {
+            let state = &machine.state;
+            let current_uptime = now_utc.saturating_sub(state.last_change());
+            let predicted_remaining = predict_remaining(state, current_uptime);
+            let uptime_score = predicted_remaining as f64 / (predicted_remaining as f64 + UPTIME_HALF_SCORE_SECS);
+
+            let (_, uptime, downtime) = state.times_since(now_utc.saturating_sub(30*86400), now_utc);
+            let reliability = match uptime + downtime {
+                0 => 0.0,
+                total => uptime as f64 / total as f64,
+            };
+
+            let info = state.extended_info.as_ref();
+            let ram = info.and_then(|info| info.hardware.ram).unwrap_or(0);
+            let threads = info.and_then(|info| info.hardware.threads).unwrap_or(0);
+            let ram_score = if max_ram > 0 { ram as f64 / max_ram as f64 } else { 0.0 };
+            let threads_score = if max_threads > 0 { threads as f64 / max_threads as f64 } else { 0.0 };
+            let hardware_score = (ram_score + threads_score) / 2.0;
+
+            Recommendation {
+                ip: machine.ip(),
+                hostname: info.map(|info| info.hostname.clone()),
+                score: UPTIME_WEIGHT * uptime_score + RELIABILITY_WEIGHT * reliability + HARDWARE_WEIGHT * hardware_score,
+                breakdown: ScoreBreakdown {
+                    current_uptime_secs: current_uptime,
+                    predicted_remaining_secs: predicted_remaining,
+                    uptime_score,
+                    reliability,
+                    hardware_score,
+                },
+            }
+        })
+        .collect();
+    recommendations.sort_by(|r1, r2| r2.score.total_cmp(&r1.score).then(r1.ip.cmp(&r2.ip)));
+    recommendations.truncate(count);
+    recommendations
+}
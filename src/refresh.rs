@@ -0,0 +1,115 @@
+use std::net::Ipv4Addr;
+use crate::MachineState;
+use crate::config::RefreshConfig;
+
+/// Why the extended info of a machine that is up should be collected again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshReason {
+    /// Never collected, for instance because the machine was already up when no username was set
+    Missing,
+    /// Older than the maximum age
+    Expired,
+    /// The last attempt failed, long enough ago to try again
+    Failed,
+    /// The info doesn't seem to describe the machine at this IP anymore:
+    /// none of its interfaces has this IP and it wasn't confirmed by a second collection yet,
+    /// or the host key changed since it was collected
+    Stale,
+}
+
+impl RefreshReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RefreshReason::Missing => "missing",
+            RefreshReason::Expired => "expired",
+            RefreshReason::Failed => "failed",
+            RefreshReason::Stale => "stale",
+        }
+    }
+}
+
+fn is_stale(ip: Ipv4Addr, state: &MachineState) -> bool {
+    let Some(info) = &state.extended_info else { return false };
+    let ip = ip.to_string();
+    let ips = info.hardware.nics.iter().flat_map(|nic| &nic.ips).map(|addr| addr.split('/').next().unwrap_or_default()).collect::<Vec<_>>();
+    // Machines behind NAT or a VPN never have the IP they are reached at, collecting the same info again confirms it
+    let confirmed = info.collected_at > info.first_collected_at;
+    let moved = !ips.is_empty() && !ips.contains(&ip.as_str()) && !confirmed;
+    let reinstalled = state.ssh_host.as_ref().and_then(|ssh| ssh.key_changed_at()).is_some_and(|changed| changed > info.collected_at);
+    moved || reinstalled
+}
+
+/// Whether the extended info of a machine should be collected again at its next check, assuming it is still up.
+/// Machines coming up always get their info collected, whatever this says.
+pub fn refresh_reason(ip: Ipv4Addr, state: &MachineState, now_utc: u64, config: &RefreshConfig) -> Option<RefreshReason> {
+    if !config.enabled || !state.up() {
+        return None;
+    }
+    if let Some((failed_at, _)) = &state.last_info_error {
+        return (now_utc.saturating_sub(*failed_at) >= config.retry_failed_secs).then_some(RefreshReason::Failed);
+    }
+    let Some(info) = &state.extended_info else { return Some(RefreshReason::Missing) };
+    if is_stale(ip, state) {
+        return Some(RefreshReason::Stale);
+    }
+    (now_utc.saturating_sub(info.collected_at) >= config.max_age_secs).then_some(RefreshReason::Expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExtendedInfo;
+    use crate::hardware::{HardwareInfo, Nic};
+    use crate::ssh::HostKeyScan;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(172, 29, 4, 12);
+
+    fn info(nic_ip: &str, collected_at: u64) -> ExtendedInfo {
+        let nic = Nic { name: String::from("eth0"), mac: None, ips: vec![format!("{nic_ip}/16")] };
+        ExtendedInfo {
+            hostname: String::from("iftpc12"),
+            hardware: HardwareInfo { nics: vec![nic], ..Default::default() },
+            raw: None,
+            collected_at,
+            first_collected_at: 0,
+        }
+    }
+
+    fn up_state() -> MachineState {
+        let mut state = MachineState::default();
+        state.checked(true, 100);
+        state
+    }
+
+    fn scan(fingerprint: &str) -> HostKeyScan {
+        HostKeyScan { banner: String::from("SSH-2.0-OpenSSH_9.2p1"), key_type: String::from("ssh-ed25519"), fingerprint: fingerprint.to_string() }
+    }
+
+    #[test]
+    fn matching_ip_is_fresh() {
+        let mut state = up_state();
+        state.record_info(info("172.29.4.12", 100));
+        assert!(!is_stale(IP, &state));
+    }
+
+    #[test]
+    fn ip_mismatch_is_stale_until_collected_again() {
+        let mut state = up_state();
+        state.record_info(info("10.8.0.3", 100));
+        assert!(is_stale(IP, &state));
+        state.record_info(info("10.8.0.3", 200));
+        assert!(!is_stale(IP, &state));
+    }
+
+    #[test]
+    fn host_key_change_is_stale_until_collected_again() {
+        let mut state = up_state();
+        state.record_info(info("172.29.4.12", 100));
+        let ssh = state.ssh_host.get_or_insert_default();
+        ssh.observe(scan("SHA256:old"), 100);
+        ssh.observe(scan("SHA256:new"), 150);
+        assert!(is_stale(IP, &state));
+        state.record_info(info("172.29.4.12", 200));
+        assert!(!is_stale(IP, &state));
+    }
+}
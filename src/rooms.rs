@@ -0,0 +1,233 @@
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::OnceLock;
+use chrono::{Datelike, Timelike};
+use regex::Regex;
+use serde::Deserialize;
+use crate::error::Error;
+use crate::targets::TargetRange;
+
+/// Catalog used when the data directory has none
+const DEFAULT_CATALOG: &str = include_str!("../rooms.toml");
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HostnamePattern(Regex);
+
+impl TryFrom<String> for HostnamePattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Regex::new(&s).map(HostnamePattern).map_err(|e| format!("Invalid hostname pattern {s:?}: {e}"))
+    }
+}
+
+/// Days of the week and a time span, such as `mon-fri 08:00-20:00`.
+/// Spans can't cross midnight, they are given as two spans instead.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct OpeningHours {
+    /// Indexed from Monday
+    days: [bool; 7],
+    /// Minutes since midnight
+    open: u32,
+    close: u32,
+}
+
+fn parse_minutes(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours <= 24 && minutes < 60 && hours * 60 + minutes <= 24 * 60).then_some(hours * 60 + minutes)
+}
+
+impl OpeningHours {
+    /// Whether a time falls within these hours, in the local time of the scanner
+    pub fn contains(&self, time_utc: u64) -> bool {
+        let Some(time) = chrono::DateTime::from_timestamp(time_utc as i64, 0) else { return false };
+        let time = time.with_timezone(&chrono::Local);
+        let day = time.weekday().num_days_from_monday() as usize;
+        let minutes = time.hour() * 60 + time.minute();
+        self.days[day] && (self.open..self.close).contains(&minutes)
+    }
+}
+
+impl TryFrom<String> for OpeningHours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid opening hours {s:?}, expected something like \"mon-fri 08:00-20:00\"");
+        let (days, span) = s.trim().split_once(' ').ok_or_else(invalid)?;
+        let day = |day: &str| DAYS.iter().position(|d| *d == day.trim());
+        let (first, last) = match days.split_once('-') {
+            Some((first, last)) => (day(first).ok_or_else(invalid)?, day(last).ok_or_else(invalid)?),
+            None => (day(days).ok_or_else(invalid)?, day(days).ok_or_else(invalid)?),
+        };
+        let (open, close) = span.trim().split_once('-').ok_or_else(invalid)?;
+        let (open, close) = (parse_minutes(open).ok_or_else(invalid)?, parse_minutes(close).ok_or_else(invalid)?);
+        if open >= close {
+            return Err(invalid());
+        }
+        let mut open_days = [false; 7];
+        // Ranges such as `sat-mon` wrap around the week
+        let mut i = first;
+        loop {
+            open_days[i] = true;
+            if i == last {
+                break;
+            }
+            i = (i + 1) % 7;
+        }
+        Ok(OpeningHours { days: open_days, open, close })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Room {
+    pub name: String,
+    pub building: Option<String>,
+    pub floor: Option<i32>,
+    /// Regular expressions matched against hostnames
+    #[serde(default)]
+    pub hostnames: Vec<HostnamePattern>,
+    #[serde(default)]
+    pub ips: Vec<TargetRange>,
+    /// Number of machines in the room
+    pub capacity: Option<u32>,
+    /// Always open when empty
+    #[serde(default)]
+    pub hours: Vec<OpeningHours>,
+}
+
+impl Room {
+    fn matches(&self, ip: Ipv4Addr, hostname: &str) -> bool {
+        (!hostname.is_empty() && self.hostnames.iter().any(|pattern| pattern.0.is_match(hostname)))
+            || self.ips.iter().any(|range| range.contains(ip))
+    }
+
+    /// Whether the room is open at a given time, in the local time of the scanner
+    pub fn is_open(&self, time_utc: u64) -> bool {
+        self.hours.is_empty() || self.hours.iter().any(|hours| hours.contains(time_utc))
+    }
+}
+
+/// Rooms machines are sorted into, from the catalog file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomCatalog {
+    /// Name of the room of machines that match no room
+    #[serde(default = "default_unknown")]
+    pub unknown: String,
+    /// The first matching room wins
+    #[serde(default)]
+    pub rooms: Vec<Room>,
+}
+
+fn default_unknown() -> String {
+    String::from("Inconnu")
+}
+
+impl Default for RoomCatalog {
+    fn default() -> Self {
+        toml::from_str(DEFAULT_CATALOG).expect("Invalid built-in room catalog")
+    }
+}
+
+impl RoomCatalog {
+    /// Reads the catalog file, or uses the built-in one when it doesn't exist
+    pub fn load(path: &Path) -> Result<RoomCatalog, Error> {
+        if !path.exists() {
+            return Ok(RoomCatalog::default());
+        }
+        let content = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let catalog: RoomCatalog = toml::from_str(&content).map_err(|e| Error::Format(format!("Invalid room catalog {}: {e}", path.display())))?;
+        for (i, room) in catalog.rooms.iter().enumerate() {
+            if catalog.rooms[..i].iter().any(|other| other.name == room.name) || room.name == catalog.unknown {
+                return Err(Error::Format(format!("Room {:?} appears twice in {}", room.name, path.display())));
+            }
+        }
+        Ok(catalog)
+    }
+
+    pub fn room_of(&self, ip: Ipv4Addr, hostname: &str) -> &str {
+        self.rooms.iter().find(|room| room.matches(ip, hostname)).map(|room| room.name.as_str()).unwrap_or(&self.unknown)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.iter().find(|room| room.name == name)
+    }
+
+    /// In catalog order, ending with the unknown room
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.rooms.iter().map(|room| room.name.as_str()).chain(std::iter::once(self.unknown.as_str()))
+    }
+}
+
+static CATALOG: OnceLock<RoomCatalog> = OnceLock::new();
+
+/// Sets the catalog used for the rest of the run, before anything looks rooms up
+pub fn init(catalog: RoomCatalog) {
+    let _ = CATALOG.set(catalog);
+}
+
+pub fn catalog() -> &'static RoomCatalog {
+    CATALOG.get_or_init(RoomCatalog::default)
+}
+
+pub fn room_of(ip: Ipv4Addr, hostname: &str) -> &'static str {
+    catalog().room_of(ip, hostname)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    fn hours(s: &str) -> Result<OpeningHours, String> {
+        OpeningHours::try_from(s.to_string())
+    }
+
+    fn room(hours: &str) -> Room {
+        toml::from_str(&format!("name = \"Salle\"\nhours = {hours}")).unwrap()
+    }
+
+    /// Local time in the week of Monday 2024-01-15, away from DST changes
+    fn local(day: u32, hour: u32, minute: u32) -> u64 {
+        chrono::Local.with_ymd_and_hms(2024, 1, 15 + day, hour, minute, 0).single().unwrap().timestamp() as u64
+    }
+
+    #[test]
+    fn parses_days_and_span() {
+        let weekdays = hours("mon-fri 08:00-20:00").unwrap();
+        assert_eq!(weekdays.days, [true, true, true, true, true, false, false]);
+        assert_eq!((weekdays.open, weekdays.close), (8 * 60, 20 * 60));
+        assert_eq!(hours("sat 09:30-12:00").unwrap().days, [false, false, false, false, false, true, false]);
+        assert_eq!(hours("fri-mon 00:00-24:00").unwrap().days, [true, false, false, false, true, true, true]);
+    }
+
+    #[test]
+    fn rejects_invalid_hours() {
+        for invalid in ["mon-fri", "monday 08:00-20:00", "mon-fri 20:00-08:00", "mon 08:00-08:00", "mon 08:60-09:00", "mon 08:00-24:01"] {
+            assert!(hours(invalid).is_err(), "{invalid} was accepted");
+        }
+    }
+
+    #[test]
+    fn open_in_local_time() {
+        let room = room("[\"mon-fri 08:00-20:00\", \"sat 09:00-12:00\"]");
+        assert!(room.is_open(local(0, 8, 0)));
+        assert!(room.is_open(local(4, 19, 59)));
+        assert!(room.is_open(local(5, 11, 0)));
+        assert!(!room.is_open(local(0, 7, 59)));
+        assert!(!room.is_open(local(2, 20, 0)));
+        assert!(!room.is_open(local(5, 12, 0)));
+        assert!(!room.is_open(local(6, 10, 0)));
+    }
+
+    #[test]
+    fn always_open_without_hours() {
+        assert!(room("[]").is_open(local(6, 3, 0)));
+    }
+}
@@ -0,0 +1,170 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::Ipv4Addr;
+use crate::{MachineState, States};
+use crate::config::ScheduleConfig;
+
+/// How often a machine is checked, from its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Up at the last check
+    Up,
+    /// Up during the last `recent_secs`
+    RecentlyUp,
+    /// Up at some point, longer ago
+    SeenOnce,
+    /// Never answered, most of the address space
+    NeverSeen,
+}
+
+pub const PRIORITIES: [Priority; 4] = [Priority::Up, Priority::RecentlyUp, Priority::SeenOnce, Priority::NeverSeen];
+
+impl Priority {
+    pub fn of(state: &MachineState, now_utc: u64, config: &ScheduleConfig) -> Priority {
+        if state.up() {
+            return Priority::Up;
+        }
+        // Machines first checked down get an empty up period, which doesn't count
+        let last_up = state.changes.chunks(2).rev().find(|pair| pair.len() == 2 && pair[1] > pair[0]).map(|pair| pair[1]);
+        match last_up {
+            Some(last_up) if now_utc.saturating_sub(last_up) <= config.recent_secs => Priority::RecentlyUp,
+            Some(_) => Priority::SeenOnce,
+            None => Priority::NeverSeen,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Up => "up",
+            Priority::RecentlyUp => "recently_up",
+            Priority::SeenOnce => "seen_once",
+            Priority::NeverSeen => "never_seen",
+        }
+    }
+
+    /// Maximum time between two checks
+    pub fn interval(&self, config: &ScheduleConfig) -> u64 {
+        match self {
+            Priority::Up => config.up_secs,
+            Priority::RecentlyUp => config.recently_up_secs,
+            Priority::SeenOnce => config.seen_once_secs,
+            Priority::NeverSeen => config.never_seen_secs,
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// When a machine should be checked next
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    pub priority: Priority,
+    pub time: u64,
+}
+
+/// Share of `config.jitter` a machine gets, the same until its next check
+fn jitter_share(ip: Ipv4Addr, last_checked: u64) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    (ip, last_checked).hash(&mut hasher);
+    (hasher.finish() % 1000) as f64 / 1000.0
+}
+
+pub fn deadline(ip: Ipv4Addr, state: &MachineState, now_utc: u64, config: &ScheduleConfig) -> Deadline {
+    let priority = Priority::of(state, now_utc, config);
+    let interval = priority.interval(config);
+    // Early rather than late, so that the interval stays a maximum
+    let jitter = (interval as f64 * config.jitter * jitter_share(ip, state.last_checked())) as u64;
+    Deadline { priority, time: state.last_checked().saturating_add(interval - jitter) }
+}
+
+/// Machines waiting for their deadline, and machines due for a check
+pub struct Queue {
+    waiting: BinaryHeap<Reverse<(u64, Priority, Ipv4Addr)>>,
+    due: BinaryHeap<Reverse<(Priority, u64, Ipv4Addr)>>,
+}
+
+impl Queue {
+    pub fn new(states: &States, now_utc: u64, config: &ScheduleConfig) -> Queue {
+        let mut queue = Queue { waiting: BinaryHeap::new(), due: BinaryHeap::new() };
+        for (ip, state) in states {
+            queue.push(*ip, deadline(*ip, state, now_utc, config));
+        }
+        queue
+    }
+
+    pub fn push(&mut self, ip: Ipv4Addr, deadline: Deadline) {
+        self.waiting.push(Reverse((deadline.time, deadline.priority, ip)));
+    }
+
+    /// Moves machines whose deadline has passed to the due ones
+    pub fn promote(&mut self, now_utc: u64) {
+        while let Some(Reverse((time, priority, ip))) = self.waiting.peek().copied() {
+            if time > now_utc {
+                break;
+            }
+            self.waiting.pop();
+            self.due.push(Reverse((priority, time, ip)));
+        }
+    }
+
+    /// The due machine to check first: highest priority, then most overdue
+    pub fn pop(&mut self) -> Option<(Ipv4Addr, Deadline)> {
+        let Reverse((priority, time, ip)) = self.due.pop()?;
+        Some((ip, Deadline { priority, time }))
+    }
+
+    /// Puts back a due machine that couldn't be checked yet
+    pub fn requeue(&mut self, ip: Ipv4Addr, deadline: Deadline) {
+        self.due.push(Reverse((deadline.priority, deadline.time, ip)));
+    }
+
+    pub fn due_count(&self) -> usize {
+        self.due.len()
+    }
+
+    /// Deadline of the next machine to become due
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.waiting.peek().map(|Reverse((time, _, _))| *time)
+    }
+}
+
+/// How far behind schedule the machines of a priority class are
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lag {
+    pub machines: usize,
+    pub overdue: usize,
+    /// Time since the deadline of the most overdue machine
+    pub max_lateness_secs: u64,
+}
+
+/// Lag of each priority class, in the order of `PRIORITIES`
+pub fn report(states: &States, now_utc: u64, config: &ScheduleConfig) -> [Lag; 4] {
+    let mut report = [Lag::default(); 4];
+    for (ip, state) in states {
+        let deadline = deadline(*ip, state, now_utc, config);
+        let lag = &mut report[deadline.priority.index()];
+        lag.machines += 1;
+        if deadline.time < now_utc {
+            lag.overdue += 1;
+            // Machines that were never checked have no meaningful lateness
+            if state.last_checked() > 0 {
+                lag.max_lateness_secs = lag.max_lateness_secs.max(now_utc - deadline.time);
+            }
+        }
+    }
+    report
+}
+
+/// One line per class that is behind schedule
+pub fn format_report(report: &[Lag; 4]) -> Vec<String> {
+    PRIORITIES.iter().zip(report)
+        .filter(|(_, lag)| lag.overdue > 0)
+        .map(|(priority, lag)| format!(
+            "{}: {}/{} machines overdue, by up to {}s",
+            priority.as_str(), lag.overdue, lag.machines, lag.max_lateness_secs,
+        ))
+        .collect()
+}
@@ -0,0 +1,40 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Set once SIGTERM or SIGINT is received
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Starts listening for signals. A second signal exits right away, without saving.
+    pub fn listen() -> Result<Shutdown, String> {
+        let mut sigterm = signal(SignalKind::terminate()).map_err(|e| format!("Failed to listen for SIGTERM: {e}"))?;
+        let mut sigint = signal(SignalKind::interrupt()).map_err(|e| format!("Failed to listen for SIGINT: {e}"))?;
+        let (sender, requested) = watch::channel(false);
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = sigterm.recv() => "SIGTERM",
+                _ = sigint.recv() => "SIGINT",
+            };
+            println!("\nReceived {name}, finishing the checks in flight before saving");
+            let _ = sender.send(true);
+            tokio::select! {
+                _ = sigterm.recv() => (),
+                _ = sigint.recv() => (),
+            }
+            eprintln!("Received a second signal, exiting without saving");
+            std::process::exit(130);
+        });
+        Ok(Shutdown { requested })
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Resolves once shutdown is requested
+    pub async fn wait(&mut self) {
+        let _ = self.requested.wait_for(|requested| *requested).await;
+    }
+}
@@ -0,0 +1,286 @@
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::{ExtendedInfo, MachineState, States};
+use crate::error::Error;
+use crate::hardware::stored_from_json;
+use crate::load::{LoadSample, MAX_SAMPLES};
+use crate::persistence::restore_state;
+use crate::storage::Storage;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS machines (
+        ip TEXT PRIMARY KEY,
+        last_checked INTEGER NOT NULL,
+        last_probe TEXT,
+        ssh_host TEXT,
+        info_error TEXT
+    );
+    CREATE TABLE IF NOT EXISTS changes (
+        ip TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        up INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS changes_ip_time ON changes (ip, time);
+    CREATE TABLE IF NOT EXISTS extended_info (
+        ip TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        hostname TEXT,
+        data TEXT
+    );
+    CREATE INDEX IF NOT EXISTS extended_info_ip_time ON extended_info (ip, time);
+    CREATE TABLE IF NOT EXISTS load_samples (
+        ip TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        load1 REAL NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS load_samples_ip_time ON load_samples (ip, time);
+";
+
+/// History stored as rows in an SQLite database: one row per state transition, per extended info snapshot and per load sample.
+/// Rows are appended as probes complete, so nothing needs to be rewritten on flush.
+/// Queries run on the blocking thread pool, so that a slow disk doesn't hold up the runtime.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+    data_dir: String,
+}
+
+fn to_json<T: serde::Serialize>(value: Option<&T>) -> Result<Option<String>, Error> {
+    value.map(serde_json::to_string).transpose().map_err(|e| Error::Format(format!("Failed to serialize: {e}")))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(data: Option<String>, ip: &str) -> Result<Option<T>, Error> {
+    data.map(|data| serde_json::from_str(&data)).transpose().map_err(|e| Error::Format(format!("Invalid data for {ip} in database: {e}")))
+}
+
+/// Rows of a machine, serialized beforehand so that they can be written on another thread
+struct MachineRows {
+    ip: String,
+    last_checked: u64,
+    last_probe: Option<String>,
+    ssh_host: Option<String>,
+    info_error: Option<String>,
+    /// (time, up)
+    changes: Vec<(u64, bool)>,
+    /// (time, hostname, data)
+    extended_info: Vec<(u64, String, String)>,
+    /// (time, load1, data)
+    load_samples: Vec<(u64, f32, String)>,
+}
+
+impl MachineRows {
+    fn new(ip: Ipv4Addr, state: &MachineState) -> Result<MachineRows, Error> {
+        Ok(MachineRows {
+            ip: ip.to_string(),
+            last_checked: state.last_checked,
+            last_probe: to_json(state.last_probe.as_ref())?,
+            ssh_host: to_json(state.ssh_host.as_ref())?,
+            info_error: to_json(state.last_info_error.as_ref())?,
+            changes: Vec::new(),
+            extended_info: Vec::new(),
+            load_samples: Vec::new(),
+        })
+    }
+
+    /// Info collected by versions that didn't record the time is dated from the last check
+    fn add_extended_info(&mut self, state: &MachineState, info: &ExtendedInfo) -> Result<(), Error> {
+        let time = match info.collected_at {
+            0 => state.last_checked,
+            collected_at => collected_at,
+        };
+        let data = serde_json::to_string(info).map_err(|e| Error::Format(format!("Failed to serialize extended info: {e}")))?;
+        self.extended_info.push((time, info.hostname.clone(), data));
+        Ok(())
+    }
+
+    fn add_load_sample(&mut self, sample: &LoadSample) -> Result<(), Error> {
+        let data = serde_json::to_string(sample).map_err(|e| Error::Format(format!("Failed to serialize load sample: {e}")))?;
+        self.load_samples.push((sample.time, sample.load[0], data));
+        Ok(())
+    }
+
+    /// All the rows of a machine
+    fn all(ip: Ipv4Addr, state: &MachineState) -> Result<MachineRows, Error> {
+        let mut rows = MachineRows::new(ip, state)?;
+        rows.changes = state.changes.iter().enumerate().map(|(i, time)| (*time, i % 2 == 0)).collect();
+        for info in state.snapshots() {
+            rows.add_extended_info(state, info)?;
+        }
+        for sample in &state.load {
+            rows.add_load_sample(sample)?;
+        }
+        Ok(rows)
+    }
+
+    fn insert(&self, conn: &Connection) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO machines (ip, last_checked, last_probe, ssh_host, info_error) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (ip) DO UPDATE SET last_checked = excluded.last_checked, last_probe = excluded.last_probe, ssh_host = excluded.ssh_host, info_error = excluded.info_error",
+            params![self.ip, self.last_checked, self.last_probe, self.ssh_host, self.info_error],
+        )?;
+        for (time, up) in &self.changes {
+            conn.execute("INSERT INTO changes (ip, time, up) VALUES (?1, ?2, ?3)", params![self.ip, time, up])?;
+        }
+        for (time, hostname, data) in &self.extended_info {
+            conn.execute("INSERT INTO extended_info (ip, time, hostname, data) VALUES (?1, ?2, ?3, ?4)", params![self.ip, time, hostname, data])?;
+        }
+        for (time, load1, data) in &self.load_samples {
+            conn.execute("INSERT INTO load_samples (ip, time, load1, data) VALUES (?1, ?2, ?3, ?4)", params![self.ip, time, load1, data])?;
+        }
+        Ok(())
+    }
+}
+
+fn is_empty(conn: &Connection) -> Result<bool, Error> {
+    let row = conn.query_row("SELECT 1 FROM machines LIMIT 1", [], |_| Ok(())).optional()?;
+    Ok(row.is_none())
+}
+
+fn read_all(conn: &Connection) -> Result<States, Error> {
+    let mut states = States::new();
+    let parse_ip = |ip: String| ip.parse::<Ipv4Addr>().map_err(|e| Error::Format(format!("Invalid IP {ip:?} in database: {e}")));
+
+    let mut stmt = conn.prepare("SELECT ip, last_checked, last_probe, ssh_host, info_error FROM machines")?;
+    let rows = stmt.query_map([], |row| Ok((
+        row.get::<_, String>(0)?,
+        row.get::<_, u64>(1)?,
+        row.get::<_, Option<String>>(2)?,
+        row.get::<_, Option<String>>(3)?,
+        row.get::<_, Option<String>>(4)?,
+    )))?;
+    for row in rows {
+        let (ip, last_checked, last_probe, ssh_host, info_error) = row?;
+        let last_probe = from_json(last_probe, &ip)?;
+        let ssh_host = from_json(ssh_host, &ip)?;
+        let info_error = from_json(info_error, &ip)?;
+        let state = states.entry(parse_ip(ip)?).or_default();
+        state.last_checked = last_checked;
+        state.last_probe = last_probe;
+        state.ssh_host = ssh_host;
+        state.last_info_error = info_error;
+    }
+
+    let mut stmt = conn.prepare("SELECT ip, time FROM changes ORDER BY ip, time, rowid")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))?;
+    for row in rows {
+        let (ip, time) = row?;
+        states.entry(parse_ip(ip)?).or_default().changes.push(time);
+    }
+
+    // Every collection has a row, the history only keeps changes.
+    // Older versions also inserted empty rows for failed collections.
+    let mut stmt = conn.prepare("SELECT ip, time, data FROM extended_info WHERE data IS NOT NULL ORDER BY ip, rowid")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?, row.get::<_, String>(2)?)))?;
+    for row in rows {
+        let (ip, time, data) = row?;
+        let mut info = stored_from_json(&data).map_err(|e| Error::Format(format!("Invalid extended info for {ip} in database: {e}")))?;
+        if info.collected_at == 0 {
+            info.collected_at = time;
+        }
+        states.entry(parse_ip(ip)?).or_default().record_info(info);
+    }
+
+    // All samples are kept in the database, only the latest ones in memory
+    let mut stmt = conn.prepare("SELECT ip, data FROM load_samples WHERE rowid IN (SELECT rowid FROM load_samples AS s WHERE s.ip = load_samples.ip ORDER BY time DESC LIMIT ?1) ORDER BY ip, time, rowid")?;
+    let rows = stmt.query_map([MAX_SAMPLES], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    for row in rows {
+        let (ip, data) = row?;
+        let sample = serde_json::from_str(&data).map_err(|e| Error::Format(format!("Invalid load sample for {ip} in database: {e}")))?;
+        states.entry(parse_ip(ip)?).or_default().add_load_sample(sample);
+    }
+
+    Ok(states)
+}
+
+fn write_all(conn: &mut Connection, machines: &[MachineRows]) -> Result<(), Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch("DELETE FROM machines; DELETE FROM changes; DELETE FROM extended_info; DELETE FROM load_samples;")?;
+    for rows in machines {
+        rows.insert(&tx)?;
+    }
+    tx.commit().map_err(Error::from)
+}
+
+fn all_rows(states: &States) -> Result<Vec<MachineRows>, Error> {
+    states.iter().map(|(ip, state)| MachineRows::all(*ip, state)).collect()
+}
+
+impl SqliteStorage {
+    /// `data_dir` is where an existing states.bin is imported from when the database is empty
+    pub fn open(path: &Path, data_dir: &str) -> Result<SqliteStorage, Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        // Databases created by older versions
+        for column in ["last_probe", "ssh_host", "info_error"] {
+            if conn.prepare(&format!("SELECT {column} FROM machines LIMIT 0")).is_err() {
+                conn.execute_batch(&format!("ALTER TABLE machines ADD COLUMN {column} TEXT"))?;
+            }
+        }
+        Ok(SqliteStorage {
+            conn: Arc::new(Mutex::new(conn)),
+            data_dir: data_dir.to_string(),
+        })
+    }
+
+    /// Runs database calls on the blocking thread pool
+    async fn blocking<T: Send + 'static>(&self, f: impl FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static) -> Result<T, Error> {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap_or_else(|e| e.into_inner())))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load(&mut self) -> Result<States, Error> {
+        if self.blocking(|conn| is_empty(conn)).await? {
+            let states = restore_state(&self.data_dir).await?;
+            if !states.is_empty() {
+                eprintln!("Importing {} machines from states.bin into the database", states.len());
+                let machines = all_rows(&states)?;
+                self.blocking(move |conn| write_all(conn, &machines)).await?;
+            }
+            return Ok(states);
+        }
+        self.blocking(|conn| read_all(conn)).await
+    }
+
+    async fn record(&mut self, ip: Ipv4Addr, state: &MachineState, previous_changes: usize, extended_info_updated: bool, load_sampled: bool) -> Result<(), Error> {
+        let mut rows = MachineRows::new(ip, state)?;
+        rows.changes = state.changes.iter().enumerate().skip(previous_changes).map(|(i, time)| (*time, i % 2 == 0)).collect();
+        if let Some(info) = state.extended_info.as_ref().filter(|_| extended_info_updated) {
+            rows.add_extended_info(state, info)?;
+        }
+        if let Some(sample) = state.load.last().filter(|_| load_sampled) {
+            rows.add_load_sample(sample)?;
+        }
+        self.blocking(move |conn| {
+            let tx = conn.transaction()?;
+            rows.insert(&tx)?;
+            tx.commit().map_err(Error::from)
+        }).await
+    }
+
+    async fn flush(&mut self, _states: &States) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn save_all(&mut self, states: &States) -> Result<(), Error> {
+        let machines = all_rows(states)?;
+        self.blocking(move |conn| write_all(conn, &machines)).await
+    }
+
+    async fn history(&self, _states: &States, ip: Ipv4Addr, from: u64, to: u64) -> Result<Vec<(u64, bool)>, Error> {
+        self.blocking(move |conn| {
+            let mut stmt = conn.prepare("SELECT time, up FROM changes WHERE ip = ?1 AND time BETWEEN ?2 AND ?3 ORDER BY time, rowid")?;
+            let rows = stmt.query_map(params![ip.to_string(), from, to], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_, _>>().map_err(Error::from)
+        }).await
+    }
+}
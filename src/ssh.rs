@@ -0,0 +1,232 @@
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use serde::{Serialize, Deserialize};
+use ssh2::{HashType, Session};
+
+/// What an SSH server tells about itself before authentication
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostKeyScan {
+    /// Identification string, such as `SSH-2.0-OpenSSH_9.2p1 Debian-2`
+    pub banner: String,
+    /// Such as `ssh-ed25519`
+    pub key_type: String,
+    /// In the format of `ssh-keygen -l`: `SHA256:` followed by the unpadded base64 of the key hash
+    pub fingerprint: String,
+}
+
+/// The algorithm name at the start of an SSH public key blob
+fn key_type(blob: &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    let name = blob.get(4..4 + len)?;
+    String::from_utf8(name.to_vec()).ok()
+}
+
+/// Why talking to an SSH server failed
+#[derive(Debug, Clone)]
+pub enum SshError {
+    Connect(String),
+    Timeout,
+    Handshake(String),
+    MissingKey(PathBuf),
+    Auth(String),
+    Command { command: String, message: String },
+    InvalidResponse(String),
+}
+
+impl std::fmt::Display for SshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SshError::Connect(e) => write!(f, "Failed to connect: {e}"),
+            SshError::Timeout => write!(f, "Timeout"),
+            SshError::Handshake(e) => write!(f, "SSH handshake failed: {e}"),
+            SshError::MissingKey(path) => write!(f, "No private key at {}", path.display()),
+            SshError::Auth(e) => write!(f, "Authentication failed: {e}"),
+            SshError::Command { command, message } => write!(f, "Command {command:?} failed: {message}"),
+            SshError::InvalidResponse(e) => write!(f, "Invalid response: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SshError {}
+
+/// libssh2 reports timeouts of blocking calls with this code
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+
+fn is_timeout(e: &ssh2::Error) -> bool {
+    e.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT)
+}
+
+/// Runs blocking libssh2 calls on the blocking thread pool
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, SshError> + Send + 'static) -> Result<T, SshError> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| Err(SshError::InvalidResponse(format!("SSH task panicked: {e}"))))
+}
+
+/// Time given to a blocking call to notice its own timeout before giving up on it
+const TIMEOUT_MARGIN: Duration = Duration::from_secs(1);
+
+/// Runs blocking libssh2 calls that end by themselves after `time_to_wait`, thanks to the session and socket timeouts.
+/// Waiting for the thread is bounded too, in case they don't.
+async fn blocking_within<T: Send + 'static>(time_to_wait: Duration, f: impl FnOnce() -> Result<T, SshError> + Send + 'static) -> Result<T, SshError> {
+    tokio::time::timeout(time_to_wait + TIMEOUT_MARGIN, blocking(f)).await.unwrap_or(Err(SshError::Timeout))
+}
+
+fn timeout_ms(time_to_wait: Duration) -> u32 {
+    time_to_wait.as_millis().clamp(1, u32::MAX as u128) as u32
+}
+
+/// A connection to an SSH server, on which several commands can be run.
+/// Every blocking call is bounded by the timeout given on connection, or by its own one.
+#[derive(Clone)]
+pub struct SshClient {
+    session: Session,
+    time_to_wait: Duration,
+}
+
+impl SshClient {
+    pub async fn connect(ip: Ipv4Addr, port: u16, time_to_wait: Duration) -> Result<SshClient, SshError> {
+        blocking_within(time_to_wait, move || {
+            let stream = TcpStream::connect_timeout(&SocketAddr::new(IpAddr::V4(ip), port), time_to_wait).map_err(|e| match e.kind() {
+                std::io::ErrorKind::TimedOut => SshError::Timeout,
+                _ => SshError::Connect(e.to_string()),
+            })?;
+            // Reads and writes outside of libssh2 timeouts, like on disconnection, don't hang either
+            stream.set_read_timeout(Some(time_to_wait)).map_err(|e| SshError::Connect(e.to_string()))?;
+            stream.set_write_timeout(Some(time_to_wait)).map_err(|e| SshError::Connect(e.to_string()))?;
+            let mut session = Session::new().map_err(|e| SshError::Handshake(e.to_string()))?;
+            session.set_timeout(timeout_ms(time_to_wait));
+            session.set_tcp_stream(stream);
+            session.handshake().map_err(|e| match is_timeout(&e) {
+                true => SshError::Timeout,
+                false => SshError::Handshake(e.to_string()),
+            })?;
+            Ok(SshClient { session, time_to_wait })
+        }).await
+    }
+
+    /// What the server presented during the handshake
+    pub fn host_key(&self) -> Result<HostKeyScan, SshError> {
+        let banner = self.session.banner().unwrap_or_default().trim_end().to_string();
+        let (blob, _) = self.session.host_key().ok_or_else(|| SshError::Handshake(String::from("Server sent no host key")))?;
+        let key_type = key_type(blob).ok_or_else(|| SshError::Handshake(String::from("Invalid host key")))?;
+        let hash = self.session.host_key_hash(HashType::Sha256).ok_or_else(|| SshError::Handshake(String::from("Failed to hash host key")))?;
+        let fingerprint = format!("SHA256:{}", STANDARD_NO_PAD.encode(hash));
+        Ok(HostKeyScan { banner, key_type, fingerprint })
+    }
+
+    /// Public key authentication with a private key file (the public key is derived from it)
+    pub async fn authenticate(&self, username: &str, private_key: &Path) -> Result<(), SshError> {
+        if !private_key.exists() {
+            return Err(SshError::MissingKey(private_key.to_path_buf()));
+        }
+        let session = self.session.clone();
+        let username = username.to_string();
+        let private_key = private_key.to_path_buf();
+        let time_to_wait = self.time_to_wait;
+        blocking_within(time_to_wait, move || {
+            session.set_timeout(timeout_ms(time_to_wait));
+            session.userauth_pubkey_file(&username, None, &private_key, None).map_err(|e| match is_timeout(&e) {
+                true => SshError::Timeout,
+                false => SshError::Auth(e.to_string()),
+            })
+        }).await
+    }
+
+    /// Runs a command on its own channel and returns its standard output
+    pub async fn exec(&self, command: &str) -> Result<String, SshError> {
+        self.exec_within(command, self.time_to_wait).await
+    }
+
+    /// Same as `exec`, for commands that take longer than the usual timeout
+    pub async fn exec_within(&self, command: &str, time_to_wait: Duration) -> Result<String, SshError> {
+        let session = self.session.clone();
+        let command = command.to_string();
+        blocking_within(time_to_wait, move || {
+            session.set_timeout(timeout_ms(time_to_wait));
+            let error = |e: ssh2::Error| match is_timeout(&e) {
+                true => SshError::Timeout,
+                false => SshError::Command { command: command.clone(), message: e.to_string() },
+            };
+            let mut channel = session.channel_session().map_err(error)?;
+            channel.exec(&command).map_err(error)?;
+            let mut stdout = String::new();
+            channel.read_to_string(&mut stdout).map_err(|e| SshError::Command { command: command.clone(), message: e.to_string() })?;
+            let mut stderr = String::new();
+            let _ = channel.stderr().read_to_string(&mut stderr);
+            channel.wait_close().map_err(error)?;
+            match channel.exit_status().map_err(error)? {
+                0 => Ok(stdout),
+                status => Err(SshError::Command { command: command.clone(), message: format!("exit status {status}: {}", stderr.trim()) }),
+            }
+        }).await
+    }
+
+    pub async fn disconnect(self) {
+        let time_to_wait = self.time_to_wait;
+        let _ = blocking_within(time_to_wait, move || {
+            self.session.set_timeout(timeout_ms(time_to_wait));
+            let _ = self.session.disconnect(None, "", None);
+            Ok(())
+        }).await;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostKey {
+    pub key_type: String,
+    pub fingerprint: String,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Keys an SSH server presented over time.
+/// A new key usually means the machine was reinstalled.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SshHost {
+    /// Latest identification string
+    pub banner: String,
+    /// Oldest first, the last one is the current key
+    pub keys: Vec<HostKey>,
+}
+
+/// Older keys are forgotten past this number
+const MAX_KEYS: usize = 10;
+
+impl SshHost {
+    pub fn current_key(&self) -> Option<&HostKey> {
+        self.keys.last()
+    }
+
+    /// Time the current key replaced another one
+    pub fn key_changed_at(&self) -> Option<u64> {
+        match self.keys.len() {
+            0 | 1 => None,
+            _ => self.keys.last().map(|key| key.first_seen),
+        }
+    }
+
+    /// Records a scan, returning the previous key if it changed
+    pub fn observe(&mut self, scan: HostKeyScan, now_utc: u64) -> Option<HostKey> {
+        self.banner = scan.banner;
+        if let Some(current) = self.keys.last_mut() {
+            if current.fingerprint == scan.fingerprint {
+                current.last_seen = now_utc;
+                return None;
+            }
+        }
+        let previous = self.keys.last().cloned();
+        self.keys.push(HostKey {
+            key_type: scan.key_type,
+            fingerprint: scan.fingerprint,
+            first_seen: now_utc,
+            last_seen: now_utc,
+        });
+        if self.keys.len() > MAX_KEYS {
+            self.keys.remove(0);
+        }
+        previous
+    }
+}
@@ -0,0 +1,72 @@
+use std::net::Ipv4Addr;
+use async_trait::async_trait;
+use crate::{MachineState, States};
+use crate::error::Error;
+use crate::config::{Config, PersistenceConfig, StorageBackend};
+use crate::persistence::{restore_state, save_states};
+use crate::sqlite::SqliteStorage;
+
+/// Where machine history is kept between runs.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn load(&mut self) -> Result<States, Error>;
+
+    /// Called after each probe.
+    /// `previous_changes` is the length of `state.changes` before the probe.
+    /// `load_sampled` tells whether the last item of `state.load` is new.
+    async fn record(&mut self, ip: Ipv4Addr, state: &MachineState, previous_changes: usize, extended_info_updated: bool, load_sampled: bool) -> Result<(), Error>;
+
+    /// Called periodically and before exiting
+    async fn flush(&mut self, states: &States) -> Result<(), Error>;
+
+    /// Rewrites everything from scratch
+    async fn save_all(&mut self, states: &States) -> Result<(), Error>;
+
+    /// State transitions of a machine between two times, as (time, up)
+    async fn history(&self, states: &States, ip: Ipv4Addr, from: u64, to: u64) -> Result<Vec<(u64, bool)>, Error> {
+        let Some(state) = states.get(&ip) else { return Ok(Vec::new()) };
+        Ok(state.changes.iter()
+            .enumerate()
+            .map(|(i, time)| (*time, i % 2 == 0))
+            .filter(|(time, _)| (from..=to).contains(time))
+            .collect())
+    }
+}
+
+/// The whole history serialized in states.bin, rewritten on every flush
+pub struct FileStorage {
+    data_dir: String,
+    config: PersistenceConfig,
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn load(&mut self) -> Result<States, Error> {
+        restore_state(&self.data_dir).await
+    }
+
+    async fn record(&mut self, _ip: Ipv4Addr, _state: &MachineState, _previous_changes: usize, _extended_info_updated: bool, _load_sampled: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn flush(&mut self, states: &States) -> Result<(), Error> {
+        save_states(states, &self.data_dir, &self.config).await
+    }
+
+    async fn save_all(&mut self, states: &States) -> Result<(), Error> {
+        self.flush(states).await
+    }
+}
+
+pub fn open_storage(config: &Config) -> Result<Box<dyn Storage>, Error> {
+    match config.storage.backend {
+        StorageBackend::File => Ok(Box::new(FileStorage {
+            data_dir: config.data_dir.clone(),
+            config: config.persistence.clone(),
+        })),
+        StorageBackend::Sqlite => {
+            let path = std::path::Path::new(&config.data_dir).join(&config.storage.sqlite_path);
+            Ok(Box::new(SqliteStorage::open(&path, &config.data_dir)?))
+        }
+    }
+}
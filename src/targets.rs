@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use serde::Deserialize;
+
+/// Refuse to expand target lists bigger than this (a /12)
+const MAX_TARGETS: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TargetRange {
+    /// `172.29.0.0/16`
+    Cidr(Ipv4Addr, u8),
+    /// `172.29.4.1-172.29.4.200`
+    Range(Ipv4Addr, Ipv4Addr),
+    /// `172.29.4.250`
+    Host(Ipv4Addr),
+}
+
+impl TargetRange {
+    /// First and last address (inclusive) as integers
+    fn bounds(&self) -> (u32, u32) {
+        match *self {
+            TargetRange::Cidr(_, 0) => (0, u32::MAX),
+            TargetRange::Cidr(ip, prefix) => {
+                let mask = u32::MAX << (32 - prefix as u32);
+                let start = u32::from(ip) & mask;
+                (start, start | !mask)
+            }
+            TargetRange::Range(start, end) => (u32::from(start), u32::from(end)),
+            TargetRange::Host(ip) => (u32::from(ip), u32::from(ip)),
+        }
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let (start, end) = self.bounds();
+        (start..=end).contains(&u32::from(ip))
+    }
+
+    pub fn size(&self) -> u64 {
+        let (start, end) = self.bounds();
+        end as u64 - start as u64 + 1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let (start, end) = self.bounds();
+        (start..=end).map(Ipv4Addr::from)
+    }
+}
+
+impl FromStr for TargetRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let parse_ip = |s: &str| s.trim().parse::<Ipv4Addr>().map_err(|e| format!("Invalid IP {s:?}: {e}"));
+        if let Some((ip, prefix)) = s.split_once('/') {
+            let prefix: u8 = prefix.trim().parse().map_err(|e| format!("Invalid prefix length in {s:?}: {e}"))?;
+            if prefix > 32 {
+                return Err(format!("Invalid prefix length in {s:?}: must be at most 32"));
+            }
+            Ok(TargetRange::Cidr(parse_ip(ip)?, prefix))
+        } else if let Some((start, end)) = s.split_once('-') {
+            let (start, end) = (parse_ip(start)?, parse_ip(end)?);
+            if start > end {
+                return Err(format!("Invalid range {s:?}: start is after end"));
+            }
+            Ok(TargetRange::Range(start, end))
+        } else {
+            Ok(TargetRange::Host(parse_ip(s)?))
+        }
+    }
+}
+
+impl TryFrom<String> for TargetRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for TargetRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetRange::Cidr(ip, prefix) => write!(f, "{ip}/{prefix}"),
+            TargetRange::Range(start, end) => write!(f, "{start}-{end}"),
+            TargetRange::Host(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// The set of addresses to scan: everything included minus everything excluded.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Targets {
+    pub include: Vec<TargetRange>,
+    pub exclude: Vec<TargetRange>,
+}
+
+impl Default for Targets {
+    fn default() -> Self {
+        Targets {
+            include: vec![TargetRange::Cidr(Ipv4Addr::new(172, 29, 0, 0), 16)],
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl Targets {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        self.include.iter().any(|r| r.contains(ip)) && !self.exclude.iter().any(|r| r.contains(ip))
+    }
+
+    pub fn generate_ips(&self) -> Result<HashSet<Ipv4Addr>, String> {
+        let total: u64 = self.include.iter().map(|r| r.size()).sum();
+        if total > MAX_TARGETS {
+            return Err(format!("Too many targets ({total} addresses, at most {MAX_TARGETS} allowed)"));
+        }
+        Ok(self.include.iter().flat_map(|r| r.iter()).filter(|ip| self.contains(*ip)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_each_form() {
+        assert_eq!("172.29.0.0/16".parse(), Ok(TargetRange::Cidr(ip("172.29.0.0"), 16)));
+        assert_eq!(" 172.29.4.1 - 172.29.4.200 ".parse(), Ok(TargetRange::Range(ip("172.29.4.1"), ip("172.29.4.200"))));
+        assert_eq!("172.29.4.250".parse(), Ok(TargetRange::Host(ip("172.29.4.250"))));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("172.29.0.0/33".parse::<TargetRange>().is_err());
+        assert!("172.29.0.0/x".parse::<TargetRange>().is_err());
+        assert!("172.29.4.200-172.29.4.1".parse::<TargetRange>().is_err());
+        assert!("172.29.4".parse::<TargetRange>().is_err());
+    }
+
+    #[test]
+    fn cidr_ignores_host_bits() {
+        let range: TargetRange = "172.29.4.17/24".parse().unwrap();
+        assert_eq!(range.size(), 256);
+        assert!(range.contains(ip("172.29.4.0")) && range.contains(ip("172.29.4.255")));
+        assert!(!range.contains(ip("172.29.5.0")));
+        assert_eq!("0.0.0.0/0".parse::<TargetRange>().unwrap().size(), 1 << 32);
+    }
+
+    #[test]
+    fn excludes_are_removed() {
+        let targets = Targets {
+            include: vec!["10.0.0.0/30".parse().unwrap()],
+            exclude: vec!["10.0.0.1-10.0.0.2".parse().unwrap()],
+        };
+        assert_eq!(targets.generate_ips(), Ok(HashSet::from([ip("10.0.0.0"), ip("10.0.0.3")])));
+    }
+
+    #[test]
+    fn too_many_targets() {
+        let at_limit = Targets { include: vec!["10.0.0.0/12".parse().unwrap()], exclude: Vec::new() };
+        assert_eq!(at_limit.generate_ips().map(|ips| ips.len() as u64), Ok(MAX_TARGETS));
+        // Excluded addresses still count, the bound is on what has to be expanded
+        let over = Targets {
+            include: vec!["10.0.0.0/12".parse().unwrap(), "10.16.0.0".parse().unwrap()],
+            exclude: vec!["10.16.0.0".parse().unwrap()],
+        };
+        assert!(over.generate_ips().is_err());
+    }
+}